@@ -1,9 +1,13 @@
-use joestar::{Callback, joestar_terminate, launch_runtime, Model, Spec, View};
+use joestar::{ExitPolicy, Joestar, Spec, View};
 use joestar_html::{AgentExt, ModelExt, button, div, h1, hflex, input, p, vflex, Length};
 
 fn main() {
     env_logger::init();
-    launch_runtime(user_main)
+    Joestar::builder()
+        .init(user_main)
+        .log_console(true)
+        .exit_policy(ExitPolicy::LastViewDestroyed)
+        .run()
 }
 
 fn user_main() {
@@ -11,12 +15,10 @@ fn user_main() {
         title: "Main".to_string(),
         size: (800, 600),
     });
-    let main_ord = main.ord();
 
-    main.on_close_request(move || {
+    main.on_close_request(|| {
         println!("See you next time!");
-        View::acquire(main_ord).unwrap().destroy();
-        joestar_terminate();
+        true
     });
 
     main.fill(vflex!(
@@ -32,17 +34,15 @@ fn user_main() {
         ),
         hflex!(
             button("Click me!")
-                .id("button1"),
+                .on_click(|detail| {
+                    println!("Click: {:#?}", detail);
+                }),
             input("text")
                 .id("input1"),
         ),
     ));
 
-    main.lookup("button1").on_click(|detail| {
-        println!("Click: {:#?}", detail);
-    });
-
     main.lookup("input1").on_input(|detail| {
         println!("Input: {:#?}", detail);
-    });
+    }).forget();
 }
\ No newline at end of file
@@ -1,6 +1,8 @@
 use std::collections::HashMap;
 use std::hash::Hash;
-use joestar::{Agent, Callback, Model};
+use joestar::{Agent, Error, joestar_report_error, Listener, Model};
+
+pub use joestar_macros::html;
 
 /// Create a new division.
 pub fn div() -> Model {
@@ -79,6 +81,17 @@ pub struct ClickDetail {
     pub modifiers: ModifierStat,
 }
 
+impl ModifierStat {
+    pub fn from_event(event: &HashMap<String, String>) -> Option<Self> {
+        Some(Self {
+            shift: event.get("shiftKey")?.parse::<bool>().ok()?,
+            ctrl: event.get("ctrlKey")?.parse::<bool>().ok()?,
+            alt: event.get("altKey")?.parse::<bool>().ok()?,
+            meta: event.get("metaKey")?.parse::<bool>().ok()?,
+        })
+    }
+}
+
 impl ClickDetail {
     pub fn from_event(event: &HashMap<String, String>) -> Option<Self> {
         let button = event.get("button")?.parse::<u8>().ok()?;
@@ -87,12 +100,7 @@ impl ClickDetail {
             event.get("clientX")?.parse::<i32>().ok()?,
             event.get("clientY")?.parse::<i32>().ok()?,
         );
-        let modifiers = ModifierStat {
-            shift: event.get("shiftKey")?.parse::<bool>().ok()?,
-            ctrl: event.get("ctrlKey")?.parse::<bool>().ok()?,
-            alt: event.get("altKey")?.parse::<bool>().ok()?,
-            meta: event.get("metaKey")?.parse::<bool>().ok()?,
-        };
+        let modifiers = ModifierStat::from_event(event)?;
         Some(Self {
             button,
             position,
@@ -101,6 +109,140 @@ impl ClickDetail {
     }
 }
 
+fn coords(event: &HashMap<String, String>, x: &str, y: &str) -> Option<(f64, f64)> {
+    Some((
+        event.get(x)?.parse::<f64>().ok()?,
+        event.get(y)?.parse::<f64>().ok()?,
+    ))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct KeyDetail {
+    pub key: String,
+    pub code: String,
+    pub repeat: bool,
+    pub modifiers: ModifierStat,
+}
+
+impl KeyDetail {
+    pub fn from_event(event: &HashMap<String, String>) -> Option<Self> {
+        Some(Self {
+            key: event.get("key")?.to_string(),
+            code: event.get("code")?.to_string(),
+            repeat: event.get("repeat")?.parse::<bool>().ok()?,
+            modifiers: ModifierStat::from_event(event)?,
+        })
+    }
+}
+
+/// Detail of a pointer event.
+///
+/// Remarks:
+/// * `buttons` is the bitmask of pressed buttons, `1` for primary, `2` for secondary, `4` for auxiliary.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PointerDetail {
+    pub pointer_id: i32,
+    pub pointer_type: String,
+    pub pressure: f64,
+    pub offset: (f64, f64),
+    pub page: (f64, f64),
+    pub screen: (f64, f64),
+    pub buttons: u16,
+    pub modifiers: ModifierStat,
+}
+
+impl PointerDetail {
+    pub fn from_event(event: &HashMap<String, String>) -> Option<Self> {
+        Some(Self {
+            pointer_id: event.get("pointerId")?.parse::<i32>().ok()?,
+            pointer_type: event.get("pointerType")?.to_string(),
+            pressure: event.get("pressure")?.parse::<f64>().ok()?,
+            offset: coords(event, "offsetX", "offsetY")?,
+            page: coords(event, "pageX", "pageY")?,
+            screen: coords(event, "screenX", "screenY")?,
+            buttons: event.get("buttons")?.parse::<u16>().ok()?,
+            modifiers: ModifierStat::from_event(event)?,
+        })
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeltaMode {
+    Pixel,
+    Line,
+    Page,
+}
+
+impl TryInto<DeltaMode> for u8 {
+    type Error = ();
+
+    fn try_into(self) -> Result<DeltaMode, Self::Error> {
+        match self {
+            0 => Ok(DeltaMode::Pixel),
+            1 => Ok(DeltaMode::Line),
+            2 => Ok(DeltaMode::Page),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WheelDetail {
+    pub delta: (f64, f64, f64),
+    pub delta_mode: DeltaMode,
+    pub modifiers: ModifierStat,
+}
+
+impl WheelDetail {
+    pub fn from_event(event: &HashMap<String, String>) -> Option<Self> {
+        let delta_mode = event.get("deltaMode")?.parse::<u8>().ok()?;
+        Some(Self {
+            delta: (
+                event.get("deltaX")?.parse::<f64>().ok()?,
+                event.get("deltaY")?.parse::<f64>().ok()?,
+                event.get("deltaZ")?.parse::<f64>().ok()?,
+            ),
+            delta_mode: delta_mode.try_into().ok()?,
+            modifiers: ModifierStat::from_event(event)?,
+        })
+    }
+}
+
+/// Detail of a focus event.
+///
+/// Remarks:
+/// * `related_id` is the ID of the element losing or gaining focus in exchange, if it has one.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FocusDetail {
+    pub related_id: Option<String>,
+}
+
+impl FocusDetail {
+    pub fn from_event(event: &HashMap<String, String>) -> Option<Self> {
+        Some(Self {
+            related_id: event.get("likit_related_id").cloned(),
+        })
+    }
+}
+
+/// Detail of a scroll event.
+///
+/// Remarks:
+/// * `scroll` is the `(scrollLeft, scrollTop)` of the scrolled element.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScrollDetail {
+    pub scroll: (f64, f64),
+}
+
+impl ScrollDetail {
+    pub fn from_event(event: &HashMap<String, String>) -> Option<Self> {
+        Some(Self {
+            scroll: coords(event, "likit_scroll_left", "likit_scroll_top")?,
+        })
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Hash)]
 pub struct ValueDetail {
     pub value: String,
@@ -123,6 +265,32 @@ impl ModelLike for Model {
     }
 }
 
+/// Declare a handler receiving the detail parsed by `parse`.
+///
+/// Remarks:
+/// * A detail failing to parse raises `Error::InvalidDetail` instead of calling back.
+fn declare_detail<D: 'static, F>(
+    model: Model,
+    key: &'static str,
+    parse: fn(&HashMap<String, String>) -> Option<D>,
+    f: F,
+) -> Model
+    where
+        F: Fn(D) + 'static,
+{
+    model.on(key, move |_, detail| match parse(&detail) {
+        Some(detail) => f(detail),
+        None => report_invalid_detail(key),
+    })
+}
+
+fn report_invalid_detail(key: &str) {
+    joestar_report_error(Error::InvalidDetail {
+        event: key.to_string(),
+        reason: "missing or malformed fields".to_string(),
+    })
+}
+
 pub trait ModelExt : ModelLike {
     fn display(self, kind: DisplayType) -> Model {
         self.model()
@@ -153,6 +321,90 @@ pub trait ModelExt : ModelLike {
         self.model()
             .style("flex", "1")
     }
+
+    fn on_click<F>(self, f: F) -> Model
+        where
+            F: Fn(ClickDetail) + 'static,
+    {
+        declare_detail(self.model(), "click", ClickDetail::from_event, f)
+    }
+
+    fn on_input<F>(self, f: F) -> Model
+        where
+            F: Fn(ValueDetail) + 'static,
+    {
+        declare_detail(self.model(), "input", ValueDetail::from_event, f)
+    }
+
+    fn on_change<F>(self, f: F) -> Model
+        where
+            F: Fn(ValueDetail) + 'static,
+    {
+        declare_detail(self.model(), "change", ValueDetail::from_event, f)
+    }
+
+    fn on_keydown<F>(self, f: F) -> Model
+        where
+            F: Fn(KeyDetail) + 'static,
+    {
+        declare_detail(self.model(), "keydown", KeyDetail::from_event, f)
+    }
+
+    fn on_keyup<F>(self, f: F) -> Model
+        where
+            F: Fn(KeyDetail) + 'static,
+    {
+        declare_detail(self.model(), "keyup", KeyDetail::from_event, f)
+    }
+
+    fn on_pointerdown<F>(self, f: F) -> Model
+        where
+            F: Fn(PointerDetail) + 'static,
+    {
+        declare_detail(self.model(), "pointerdown", PointerDetail::from_event, f)
+    }
+
+    fn on_pointerup<F>(self, f: F) -> Model
+        where
+            F: Fn(PointerDetail) + 'static,
+    {
+        declare_detail(self.model(), "pointerup", PointerDetail::from_event, f)
+    }
+
+    fn on_pointermove<F>(self, f: F) -> Model
+        where
+            F: Fn(PointerDetail) + 'static,
+    {
+        declare_detail(self.model(), "pointermove", PointerDetail::from_event, f)
+    }
+
+    fn on_wheel<F>(self, f: F) -> Model
+        where
+            F: Fn(WheelDetail) + 'static,
+    {
+        declare_detail(self.model(), "wheel", WheelDetail::from_event, f)
+    }
+
+    fn on_focus<F>(self, f: F) -> Model
+        where
+            F: Fn(FocusDetail) + 'static,
+    {
+        declare_detail(self.model(), "focus", FocusDetail::from_event, f)
+    }
+
+    fn on_blur<F>(self, f: F) -> Model
+        where
+            F: Fn(FocusDetail) + 'static,
+    {
+        declare_detail(self.model(), "blur", FocusDetail::from_event, f)
+    }
+
+    fn on_scroll<F>(self, f: F) -> Model
+        where
+            F: Fn(ScrollDetail) + 'static,
+    {
+        declare_detail(self.model(), "scroll", ScrollDetail::from_event, f)
+    }
 }
 
 impl<T: ModelLike> ModelExt for T {}
@@ -242,35 +494,108 @@ impl AgentLike for Agent {
     }
 }
 
+/// Bind a callback receiving the detail parsed by `parse`.
+///
+/// Remarks:
+/// * A detail failing to parse raises `Error::InvalidDetail` instead of calling back.
+fn bind_detail<D: 'static, F>(
+    agent: &Agent,
+    key: &'static str,
+    parse: fn(&HashMap<String, String>) -> Option<D>,
+    f: F,
+) -> Listener
+    where
+        F: Fn(D) + 'static,
+{
+    agent.bind(key, move |_, detail| match parse(&detail) {
+        Some(detail) => f(detail),
+        None => report_invalid_detail(key),
+    })
+}
+
 pub trait AgentExt : AgentLike {
-    fn on_click<F>(&self, f: F) -> Callback
+    fn on_click<F>(&self, f: F) -> Listener
         where
             F: Fn(ClickDetail) + 'static,
     {
-        self.as_agent().bind("click", move |agent, detail| {
-            let detail = ClickDetail::from_event(&detail).unwrap();
-            f(detail);
-        })
+        bind_detail(self.as_agent(), "click", ClickDetail::from_event, f)
     }
 
-    fn on_input<F>(&self, f: F) -> Callback
+    fn on_input<F>(&self, f: F) -> Listener
         where
             F: Fn(ValueDetail) + 'static,
     {
-        self.as_agent().bind("input", move |agent, detail| {
-            let detail = ValueDetail::from_event(&detail).unwrap();
-            f(detail);
-        })
+        bind_detail(self.as_agent(), "input", ValueDetail::from_event, f)
     }
 
-    fn on_change<F>(&self, f: F) -> Callback
+    fn on_change<F>(&self, f: F) -> Listener
         where
             F: Fn(ValueDetail) + 'static,
     {
-        self.as_agent().bind("change", move |agent, detail| {
-            let detail = ValueDetail::from_event(&detail).unwrap();
-            f(detail);
-        })
+        bind_detail(self.as_agent(), "change", ValueDetail::from_event, f)
+    }
+
+    fn on_keydown<F>(&self, f: F) -> Listener
+        where
+            F: Fn(KeyDetail) + 'static,
+    {
+        bind_detail(self.as_agent(), "keydown", KeyDetail::from_event, f)
+    }
+
+    fn on_keyup<F>(&self, f: F) -> Listener
+        where
+            F: Fn(KeyDetail) + 'static,
+    {
+        bind_detail(self.as_agent(), "keyup", KeyDetail::from_event, f)
+    }
+
+    fn on_pointerdown<F>(&self, f: F) -> Listener
+        where
+            F: Fn(PointerDetail) + 'static,
+    {
+        bind_detail(self.as_agent(), "pointerdown", PointerDetail::from_event, f)
+    }
+
+    fn on_pointerup<F>(&self, f: F) -> Listener
+        where
+            F: Fn(PointerDetail) + 'static,
+    {
+        bind_detail(self.as_agent(), "pointerup", PointerDetail::from_event, f)
+    }
+
+    fn on_pointermove<F>(&self, f: F) -> Listener
+        where
+            F: Fn(PointerDetail) + 'static,
+    {
+        bind_detail(self.as_agent(), "pointermove", PointerDetail::from_event, f)
+    }
+
+    fn on_wheel<F>(&self, f: F) -> Listener
+        where
+            F: Fn(WheelDetail) + 'static,
+    {
+        bind_detail(self.as_agent(), "wheel", WheelDetail::from_event, f)
+    }
+
+    fn on_focus<F>(&self, f: F) -> Listener
+        where
+            F: Fn(FocusDetail) + 'static,
+    {
+        bind_detail(self.as_agent(), "focus", FocusDetail::from_event, f)
+    }
+
+    fn on_blur<F>(&self, f: F) -> Listener
+        where
+            F: Fn(FocusDetail) + 'static,
+    {
+        bind_detail(self.as_agent(), "blur", FocusDetail::from_event, f)
+    }
+
+    fn on_scroll<F>(&self, f: F) -> Listener
+        where
+            F: Fn(ScrollDetail) + 'static,
+    {
+        bind_detail(self.as_agent(), "scroll", ScrollDetail::from_event, f)
     }
 }
 
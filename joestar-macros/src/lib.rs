@@ -0,0 +1,425 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{quote, quote_spanned};
+use syn::ext::IdentExt;
+use syn::parse::{Parse, ParseStream, Parser};
+use syn::{braced, Error, Expr, Ident, Lit, LitStr, Pat, Result, Token};
+
+/// Build a Model with an HTML-like syntax.
+///
+/// Syntax:
+/// * Elements: `<div>...</div>`, or `<input/>` without children.
+/// * Attributes: `class="row"`, `value={expr}`, or `disabled` without a value.
+///     * `id` sets the ID of the Model.
+///     * `style:color="red"` sets a style.
+///     * `on:click={handler}` declares a handler, see `Model::on`.
+/// * Children: elements, string literals as text, and `{expr}` for anything implementing `ModelChild`.
+///     * `{for pattern in expr { ... }}` repeats its children.
+///     * `{if cond { ... } else { ... }}` chooses its children, `else if` is supported.
+///
+/// Remarks:
+/// * It takes a single root element and expands to builder calls on `joestar::Model`.
+///     * So `joestar` must be a dependency.
+/// * Unknown tags and attributes are rejected at compile time.
+///     * Custom elements (with a `-` in the tag) and `data-*`/`aria-*` attributes are always accepted.
+/// * Mismatched end tags, duplicate attributes and children of void elements are rejected as well.
+#[proc_macro]
+pub fn html(input: TokenStream) -> TokenStream {
+    match parse_root.parse(input) {
+        Ok(element) => element.expand().into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+/// Known HTML elements.
+const TAGS: &[&str] = &[
+    "a", "abbr", "address", "area", "article", "aside", "audio", "b", "base", "bdi", "bdo",
+    "blockquote", "body", "br", "button", "canvas", "caption", "cite", "code", "col",
+    "colgroup", "data", "datalist", "dd", "del", "details", "dfn", "dialog", "div", "dl", "dt",
+    "em", "embed", "fieldset", "figcaption", "figure", "footer", "form", "h1", "h2", "h3", "h4",
+    "h5", "h6", "head", "header", "hgroup", "hr", "html", "i", "iframe", "img", "input", "ins",
+    "kbd", "label", "legend", "li", "link", "main", "map", "mark", "menu", "meta", "meter",
+    "nav", "noscript", "object", "ol", "optgroup", "option", "output", "p", "picture", "pre",
+    "progress", "q", "rp", "rt", "ruby", "s", "samp", "script", "search", "section", "select",
+    "slot", "small", "source", "span", "strong", "style", "sub", "summary", "sup", "table",
+    "tbody", "td", "template", "textarea", "tfoot", "th", "thead", "time", "title", "tr",
+    "track", "u", "ul", "var", "video", "wbr",
+    "svg", "g", "path", "circle", "ellipse", "line", "polyline", "polygon", "rect", "text",
+    "tspan", "defs", "use", "symbol", "clipPath", "linearGradient", "radialGradient", "stop",
+];
+
+/// Elements that have no content and no end tag.
+const VOID_TAGS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img",
+    "input", "link", "meta", "source", "track", "wbr",
+];
+
+/// Known HTML attributes, global or specific to some elements.
+const ATTRIBUTES: &[&str] = &[
+    "accesskey", "autocapitalize", "autofocus", "class", "contenteditable", "dir", "draggable",
+    "enterkeyhint", "hidden", "inert", "inputmode", "is", "itemid", "itemprop", "itemref",
+    "itemscope", "itemtype", "lang", "nonce", "popover", "role", "slot", "spellcheck",
+    "tabindex", "title", "translate",
+    "accept", "accept-charset", "action", "allow", "alt", "async", "autocomplete", "autoplay",
+    "charset", "checked", "cite", "cols", "colspan", "content", "controls", "coords",
+    "crossorigin", "datetime", "decoding", "default", "defer", "dirname", "disabled",
+    "download", "enctype", "for", "form", "formaction", "formenctype", "formmethod",
+    "formnovalidate", "formtarget", "headers", "height", "high", "href", "hreflang",
+    "http-equiv", "integrity", "kind", "label", "list", "loading", "loop", "low", "max",
+    "maxlength", "media", "method", "min", "minlength", "multiple", "muted", "name",
+    "novalidate", "open", "optimum", "pattern", "ping", "placeholder", "playsinline", "poster",
+    "preload", "readonly", "referrerpolicy", "rel", "required", "reversed", "rows", "rowspan",
+    "sandbox", "scope", "selected", "shape", "size", "sizes", "span", "src", "srcdoc",
+    "srclang", "srcset", "start", "step", "target", "type", "usemap", "value", "width", "wrap",
+    "viewBox", "xmlns", "d", "fill", "stroke", "stroke-width", "cx", "cy", "r", "rx", "ry",
+    "x", "y", "x1", "y1", "x2", "y2", "points", "transform", "offset", "stop-color",
+];
+
+struct Element {
+    name: String,
+    span: Span,
+    attrs: Vec<Attr>,
+    children: Vec<Child>,
+}
+
+enum Attr {
+    Id(Value),
+    Plain(String, Option<Value>),
+    Style(String, Value),
+    On(String, Box<Expr>),
+}
+
+enum Value {
+    Lit(Lit),
+    Expr(Box<Expr>),
+}
+
+enum Child {
+    Element(Element),
+    Text(Lit),
+    Expr(Expr),
+    For(Pat, Expr, Vec<Child>),
+    If(If),
+}
+
+struct If {
+    cond: Expr,
+    then: Vec<Child>,
+    otherwise: Option<Else>,
+}
+
+enum Else {
+    If(Box<If>),
+    Block(Vec<Child>),
+}
+
+impl Parse for Element {
+    fn parse(input: ParseStream) -> Result<Self> {
+        input.parse::<Token![<]>()?;
+        let (name, span) = parse_name(input)?;
+        if !TAGS.contains(&name.as_str()) && !name.contains('-') {
+            return Err(Error::new(span, format!("unknown element `{}`", name)));
+        }
+        let mut attrs = vec![];
+        let mut seen = vec![];
+        while !input.peek(Token![>]) && !input.peek(Token![/]) {
+            let (attr, key, key_span) = parse_attr(input)?;
+            if seen.contains(&key) {
+                return Err(Error::new(key_span, format!("duplicate attribute `{}`", key)));
+            }
+            seen.push(key);
+            attrs.push(attr);
+        }
+        let void = VOID_TAGS.contains(&name.as_str());
+        if input.peek(Token![/]) {
+            input.parse::<Token![/]>()?;
+            input.parse::<Token![>]>()?;
+            return Ok(Element { name, span, attrs, children: vec![] });
+        }
+        input.parse::<Token![>]>()?;
+        if void {
+            return Ok(Element { name, span, attrs, children: vec![] });
+        }
+        let mut children = vec![];
+        while !(input.peek(Token![<]) && input.peek2(Token![/])) {
+            if input.is_empty() {
+                return Err(Error::new(span, format!("unclosed element `{}`", name)));
+            }
+            children.push(input.parse()?);
+        }
+        input.parse::<Token![<]>()?;
+        input.parse::<Token![/]>()?;
+        let (end_name, end_span) = parse_name(input)?;
+        if VOID_TAGS.contains(&end_name.as_str()) {
+            return Err(Error::new(
+                end_span,
+                format!("void element `{}` can't have children or an end tag", end_name),
+            ));
+        }
+        if end_name != name {
+            return Err(Error::new(
+                end_span,
+                format!("expected `</{}>`, found `</{}>`", name, end_name),
+            ));
+        }
+        input.parse::<Token![>]>()?;
+        Ok(Element { name, span, attrs, children })
+    }
+}
+
+impl Parse for Child {
+    fn parse(input: ParseStream) -> Result<Self> {
+        if input.peek(Token![<]) {
+            let element: Element = input.parse()?;
+            return Ok(Child::Element(element));
+        }
+        if input.peek(Lit) {
+            return Ok(Child::Text(input.parse()?));
+        }
+        let content;
+        braced!(content in input);
+        let child = if content.peek(Token![for]) {
+            content.parse::<Token![for]>()?;
+            let pat: Pat = content.parse()?;
+            content.parse::<Token![in]>()?;
+            let iter = Expr::parse_without_eager_brace(&content)?;
+            Child::For(pat, iter, parse_block(&content)?)
+        } else if content.peek(Token![if]) {
+            Child::If(content.parse()?)
+        } else {
+            Child::Expr(content.parse()?)
+        };
+        if !content.is_empty() {
+            return Err(content.error("unexpected tokens after embedded expression"));
+        }
+        Ok(child)
+    }
+}
+
+impl Parse for If {
+    fn parse(input: ParseStream) -> Result<Self> {
+        input.parse::<Token![if]>()?;
+        let cond = Expr::parse_without_eager_brace(input)?;
+        let then = parse_block(input)?;
+        let otherwise = if input.peek(Token![else]) {
+            input.parse::<Token![else]>()?;
+            if input.peek(Token![if]) {
+                Some(Else::If(Box::new(input.parse()?)))
+            } else {
+                Some(Else::Block(parse_block(input)?))
+            }
+        } else {
+            None
+        };
+        Ok(If { cond, then, otherwise })
+    }
+}
+
+/// Parse children enclosed in braces.
+fn parse_block(input: ParseStream) -> Result<Vec<Child>> {
+    let content;
+    braced!(content in input);
+    let mut children = vec![];
+    while !content.is_empty() {
+        children.push(content.parse()?);
+    }
+    Ok(children)
+}
+
+/// Parse the single root element of the input.
+fn parse_root(input: ParseStream) -> Result<Element> {
+    let element = input.parse()?;
+    if !input.is_empty() {
+        return Err(input.error("expected a single root element"));
+    }
+    Ok(element)
+}
+
+/// Parse a name made of identifiers and hyphens, like `data-role` or `--gap`.
+fn parse_name(input: ParseStream) -> Result<(String, Span)> {
+    let span = input.span();
+    let mut name = String::new();
+    loop {
+        if input.peek(Token![-]) {
+            input.parse::<Token![-]>()?;
+            name.push('-');
+            continue;
+        }
+        if !name.is_empty() && !name.ends_with('-') {
+            break;
+        }
+        let ident = input.call(Ident::parse_any)?;
+        name.push_str(&ident.to_string());
+        if !input.peek(Token![-]) {
+            break;
+        }
+    }
+    Ok((name, span))
+}
+
+/// Parse an attribute, returning it along with the key used to detect duplicates.
+fn parse_attr(input: ParseStream) -> Result<(Attr, String, Span)> {
+    let (name, span) = parse_name(input)?;
+    let namespace = if input.peek(Token![:]) && !input.peek(Token![::]) {
+        input.parse::<Token![:]>()?;
+        Some(name.clone())
+    } else {
+        None
+    };
+    let name = if namespace.is_some() { parse_name(input)?.0 } else { name };
+    let value = if input.peek(Token![=]) {
+        input.parse::<Token![=]>()?;
+        Some(parse_value(input)?)
+    } else {
+        None
+    };
+    let key = match &namespace {
+        Some(namespace) => format!("{}:{}", namespace, name),
+        None => name.clone(),
+    };
+    let attr = match (namespace.as_deref(), value) {
+        (Some("style"), Some(value)) => Attr::Style(name, value),
+        (Some("on"), Some(Value::Expr(handler))) => Attr::On(name, handler),
+        (Some("style" | "on"), _) => {
+            return Err(Error::new(span, format!("`{}` needs a value", key)));
+        }
+        (Some(namespace), _) => {
+            return Err(Error::new(span, format!("unknown attribute namespace `{}`", namespace)));
+        }
+        (None, Some(value)) if name == "id" => Attr::Id(value),
+        (None, _) if name == "id" => {
+            return Err(Error::new(span, "`id` needs a value"));
+        }
+        (None, value) => {
+            let known = ATTRIBUTES.contains(&name.as_str())
+                || name.starts_with("data-")
+                || name.starts_with("aria-");
+            if !known {
+                return Err(Error::new(span, format!("unknown attribute `{}`", name)));
+            }
+            Attr::Plain(name, value)
+        }
+    };
+    Ok((attr, key, span))
+}
+
+/// Parse a literal or an expression in braces.
+fn parse_value(input: ParseStream) -> Result<Value> {
+    if input.peek(Lit) {
+        return Ok(Value::Lit(input.parse()?));
+    }
+    let content;
+    braced!(content in input);
+    Ok(Value::Expr(Box::new(content.parse()?)))
+}
+
+/// Name of the Model being built, hidden from the embedded expressions.
+fn model_ident() -> Ident {
+    Ident::new("__jo_model", Span::mixed_site())
+}
+
+impl Element {
+    fn expand(&self) -> TokenStream2 {
+        let model = model_ident();
+        let name = &self.name;
+        let attrs = self.attrs.iter().map(Attr::expand);
+        let children = expand_children(&self.children);
+        let new = quote_spanned! { self.span=> ::joestar::Model::new(#name) };
+        quote! {
+            {
+                let mut #model = #new;
+                #(#model = #model #attrs;)*
+                #children
+                #model
+            }
+        }
+    }
+}
+
+impl Attr {
+    fn expand(&self) -> TokenStream2 {
+        match self {
+            Attr::Id(value) => {
+                let value = value.expand_string();
+                quote! { .id(#value) }
+            }
+            Attr::Plain(name, None) => quote! { .attr(#name, true) },
+            Attr::Plain(name, Some(Value::Lit(Lit::Bool(value)))) => quote! { .attr(#name, #value) },
+            Attr::Plain(name, Some(value)) => {
+                let value = value.expand_string();
+                quote! { .attr(#name, #value) }
+            }
+            Attr::Style(name, value) => {
+                let value = value.expand_string();
+                quote! { .style(#name, #value) }
+            }
+            Attr::On(name, handler) => quote! { .on(#name, #handler) },
+        }
+    }
+}
+
+impl Value {
+    /// Expand to a string for literals, or to the expression as it is.
+    fn expand_string(&self) -> TokenStream2 {
+        match self {
+            Value::Lit(lit) => {
+                let text = lit_text(lit);
+                quote! { #text }
+            }
+            Value::Expr(expr) => quote! { #expr },
+        }
+    }
+}
+
+/// Get the text of a literal, without quotes for string literals.
+fn lit_text(lit: &Lit) -> LitStr {
+    match lit {
+        Lit::Str(lit) => lit.clone(),
+        Lit::Char(lit) => LitStr::new(&lit.value().to_string(), lit.span()),
+        Lit::Int(lit) => LitStr::new(lit.base10_digits(), lit.span()),
+        Lit::Float(lit) => LitStr::new(lit.base10_digits(), lit.span()),
+        Lit::Bool(lit) => LitStr::new(&lit.value.to_string(), lit.span),
+        lit => LitStr::new(&quote!(#lit).to_string(), lit.span()),
+    }
+}
+
+fn expand_children(children: &[Child]) -> TokenStream2 {
+    let model = model_ident();
+    children.iter().map(|child| match child {
+        Child::Element(element) => {
+            let element = element.expand();
+            quote! { #model = #model.child(#element); }
+        }
+        Child::Text(lit) => {
+            let text = lit_text(lit);
+            quote! { #model = #model.text(#text); }
+        }
+        Child::Expr(expr) => quote! {
+            #model = ::joestar::ModelChild::add_to(#expr, #model);
+        },
+        Child::For(pat, iter, body) => {
+            let body = expand_children(body);
+            quote! { for #pat in #iter { #body } }
+        }
+        Child::If(branch) => branch.expand(),
+    }).collect()
+}
+
+impl If {
+    fn expand(&self) -> TokenStream2 {
+        let cond = &self.cond;
+        let then = expand_children(&self.then);
+        let otherwise = match &self.otherwise {
+            Some(Else::If(branch)) => {
+                let branch = branch.expand();
+                quote! { else #branch }
+            }
+            Some(Else::Block(body)) => {
+                let body = expand_children(body);
+                quote! { else { #body } }
+            }
+            None => quote! {},
+        };
+        quote! { if #cond { #then } #otherwise }
+    }
+}
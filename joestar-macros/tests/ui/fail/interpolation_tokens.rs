@@ -0,0 +1,6 @@
+use joestar_macros::html;
+
+fn main() {
+    let count = 1;
+    let _ = html! { <p>{count count}</p> };
+}
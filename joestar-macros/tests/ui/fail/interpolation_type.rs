@@ -0,0 +1,7 @@
+use joestar_macros::html;
+
+struct Point;
+
+fn main() {
+    let _ = html! { <p>{Point}</p> };
+}
@@ -0,0 +1,5 @@
+use joestar_macros::html;
+
+fn main() {
+    let _ = html! { <div><p>"Hi"</span></div> };
+}
@@ -0,0 +1,5 @@
+use joestar_macros::html;
+
+fn main() {
+    let _ = html! { <p>"One"</p><p>"Two"</p> };
+}
@@ -0,0 +1,5 @@
+use joestar_macros::html;
+
+fn main() {
+    let _ = html! { <div bogus="1"></div> };
+}
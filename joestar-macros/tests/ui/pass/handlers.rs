@@ -0,0 +1,9 @@
+use joestar::Model;
+use joestar_macros::html;
+
+fn main() {
+    let model: Model = html! {
+        <button type="button" on:click={|_, _| {}}>"Click me!"</button>
+    };
+    assert_eq!(model.to_html(), r#"<button type="button">Click me!</button>"#);
+}
@@ -0,0 +1,29 @@
+use joestar::Model;
+use joestar_macros::html;
+
+fn main() {
+    let title = "Fruits";
+    let items = vec!["Apple", "Banana"];
+    let count = items.len();
+    let empty = false;
+    let model: Model = html! {
+        <div id="fruits" class="list" style:padding="8px" data-count={count.to_string()}>
+            <h1 title={title}>{title}" & more"</h1>
+            <ul>{for (i, item) in items.iter().enumerate() { <li value={i.to_string()}>{*item}</li> }}</ul>
+            {if empty { <p>"None"</p> } else if count > 1 { <p>{count}" fruits"</p> } else { "One" }}
+            <input type="checkbox" checked/>
+            <my-widget aria-label="custom"></my-widget>
+            {Some(Model::new("hr"))}
+        </div>
+    };
+    assert_eq!(model.to_html(), concat!(
+        r#"<div id="fruits" class="list" data-count="2" style="padding: 8px; ">"#,
+        r#"<h1 title="Fruits">Fruits &amp; more</h1>"#,
+        r#"<ul><li value="0">Apple</li><li value="1">Banana</li></ul>"#,
+        r#"<p>2 fruits</p>"#,
+        r#"<input type="checkbox" checked>"#,
+        r#"<my-widget aria-label="custom"></my-widget>"#,
+        r#"<hr>"#,
+        r#"</div>"#,
+    ));
+}
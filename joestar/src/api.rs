@@ -1,7 +1,17 @@
-use std::cell::RefCell;
-use std::collections::{BTreeMap, HashMap};
-use std::fmt::{Debug};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::{Debug, Display, Formatter, Write};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+#[cfg(feature = "serde")]
+use serde::de::DeserializeOwned;
 use std::thread;
 
 use crate::rt::*;
@@ -17,28 +27,271 @@ pub struct Spec {
     pub size: (u32, u32),
 }
 
+impl Default for Spec {
+    fn default() -> Self {
+        Self {
+            title: "Joestar".to_string(),
+            size: (800, 600),
+        }
+    }
+}
+
+/// Error raised on user thread.
+///
+/// Variants:
+/// * Panic: A callback or the initializer panicked with the given message.
+/// * StaleView: A handle to a destroyed WebView was used.
+/// * InvalidDetail: The detail of an event could not be turned into the expected type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Panic(String),
+    StaleView(ViewId),
+    InvalidDetail {
+        event: String,
+        reason: String,
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Panic(message) => write!(f, "user code panicked: {message}"),
+            Error::StaleView(id) => write!(f, "view {id} has been destroyed"),
+            Error::InvalidDetail { event, reason } =>
+                write!(f, "invalid detail of event `{event}`: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Identifier of a WebView.
+///
+/// Remarks:
+/// * The index of a destroyed WebView is reused with its generation bumped.
+///     * A handle kept past `destroy` never addresses a newer WebView by accident.
+/// * It is written as `index.generation`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ViewId {
+    index: usize,
+    generation: u32,
+}
+
+impl ViewId {
+    /// Get the index of the slot.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Get the generation of the slot.
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+impl Display for ViewId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.index, self.generation)
+    }
+}
+
+impl FromStr for ViewId {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (index, generation) = s.split_once('.').ok_or(())?;
+        Ok(Self {
+            index: index.parse().map_err(|_| ())?,
+            generation: generation.parse().map_err(|_| ())?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ViewSlot {
+    generation: u32,
+    alive: bool,
+}
+
 thread_local! {
-    static VIEW_ID_NEXT: AtomicUsize = AtomicUsize::new(0);
-    static VIEW_CUR: RefCell<Vec<usize>> = RefCell::new(Vec::new());
+    static VIEW_SLOTS: RefCell<Vec<ViewSlot>> = const { RefCell::new(Vec::new()) };
     static VIEW_EVENTS: RefCell<BTreeMap<usize, BTreeMap<String, usize>>> = RefCell::new(BTreeMap::new());
+    static MOUNTED: RefCell<BTreeMap<ViewId, Vec<Listener>>> = const { RefCell::new(BTreeMap::new()) };
+    static REPLIES: RefCell<BTreeMap<u64, (ViewId, ReplyFunc)>> = const { RefCell::new(BTreeMap::new()) };
+    static SCRIPT_QUEUE: RefCell<Vec<(ViewId, String)>> = const { RefCell::new(Vec::new()) };
+    static BATCH_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+static REPLY_ID_NEXT: AtomicU64 = AtomicU64::new(0);
+
+type ReplyFunc = Box<dyn FnOnce(String)>;
+
+fn next_view_id() -> ViewId {
+    VIEW_SLOTS.with(|slots| {
+        let mut slots = slots.borrow_mut();
+        if let Some(index) = slots.iter().position(|slot| !slot.alive) {
+            let slot = &mut slots[index];
+            slot.generation = slot.generation.wrapping_add(1);
+            slot.alive = true;
+            ViewId { index, generation: slot.generation }
+        } else {
+            slots.push(ViewSlot { generation: 0, alive: true });
+            ViewId { index: slots.len() - 1, generation: 0 }
+        }
+    })
+}
+
+fn is_view_alive(id: ViewId) -> bool {
+    VIEW_SLOTS.with(|slots| slots.borrow().get(id.index)
+        .is_some_and(|slot| slot.alive && slot.generation == id.generation))
+}
+
+fn live_views() -> Vec<ViewId> {
+    VIEW_SLOTS.with(|slots| slots.borrow().iter().enumerate()
+        .filter(|(_, slot)| slot.alive)
+        .map(|(index, slot)| ViewId { index, generation: slot.generation })
+        .collect())
+}
+
+fn remove_cur_view(id: ViewId) {
+    VIEW_SLOTS.with(|slots| {
+        if let Some(slot) = slots.borrow_mut().get_mut(id.index) {
+            if slot.generation == id.generation {
+                slot.alive = false;
+            }
+        }
+    });
 }
 
-fn next_view_id() -> usize {
-    VIEW_ID_NEXT.with(|id| id.fetch_add(1, Ordering::SeqCst))
+/// Forget a WebView that has been destroyed by the runtime.
+pub(crate) fn forget_view(id: ViewId) {
+    remove_cur_view(id);
+    drop(unmount(id));
+    remove_owned_callbacks(id);
+    REPLIES.with(|replies| replies.borrow_mut().retain(|_, (owner, _)| *owner != id));
 }
 
-fn add_cur_view(id: usize) {
-    VIEW_CUR.with(|cur| cur.borrow_mut().push(id));
+/// Queue a script to be evaluated in a WebView.
+///
+/// Remarks:
+/// * Scripts queued for the same WebView are coalesced into a single evaluation, keeping order.
+///     * Each one is wrapped in its own `try` block and logs its error to the console.
+///     * So a throwing script doesn't skip the ones after it, e.g. the reply of `View::flush`.
+/// * The queue is sent at the end of every task of user thread, outside of `View::batch` scopes.
+/// * If the WebView is already destroyed, nothing is queued and `Error::StaleView` is raised.
+fn queue_script(id: ViewId, script: String) -> bool {
+    if !is_view_alive(id) {
+        raise_error(Error::StaleView(id));
+        return false;
+    }
+    SCRIPT_QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        let queued = match queue.iter_mut().find(|(owner, _)| *owner == id) {
+            Some((_, queued)) => {
+                queued.push('\n');
+                queued
+            }
+            None => {
+                queue.push((id, String::with_capacity(script.len() + 32)));
+                &mut queue.last_mut().unwrap().1
+            }
+        };
+        queued.push_str("try{\n");
+        queued.push_str(&script);
+        queued.push_str("\n}catch(e){console.error(e)}");
+    });
+    true
+}
+
+/// Make a View that is alive without a WebView behind it, for tests.
+#[cfg(test)]
+pub(crate) fn detached_view() -> View {
+    View { ord: next_view_id() }
+}
+
+/// Take the script queued for a WebView instead of sending it, for tests.
+#[cfg(test)]
+pub(crate) fn take_queued_script(id: ViewId) -> String {
+    SCRIPT_QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        let index = queue.iter().position(|(owner, _)| *owner == id);
+        index.map(|index| queue.remove(index).1).unwrap_or_default()
+    })
 }
 
-fn remove_cur_view(id: usize) {
-    VIEW_CUR.with(|cur| {
-        let mut cur = cur.borrow_mut();
-        let index = cur.iter().position(|x| *x == id).unwrap();
-        cur.swap_remove(index);
+/// Send the queued scripts to the runtime, one evaluation per WebView.
+pub(crate) fn flush_scripts() {
+    let queue = SCRIPT_QUEUE.with(|queue| queue.take());
+    if queue.is_empty() {
+        return;
+    }
+    PROXY.with(move |proxy| {
+        let proxy = proxy.borrow();
+        let proxy = proxy.as_ref().unwrap();
+        for (ord, script) in queue {
+            proxy.send_event(JoEvent::EvalScript { ord, script }).unwrap();
+        }
     });
 }
 
+/// Send the queued scripts at the end of a task, unless a `View::batch` scope is open.
+pub(crate) fn end_of_task() {
+    if BATCH_DEPTH.with(|depth| depth.get()) == 0 {
+        flush_scripts();
+    }
+}
+
+/// Ask the page of a WebView to evaluate an expression and send its string value back.
+///
+/// Remarks:
+/// * The reply is sent after every script previously sent to the WebView has run.
+/// * The function is invoked on user thread with the reply, unless the WebView is destroyed first.
+pub(crate) fn request_reply<F>(id: ViewId, expression: &str, f: F)
+    where
+        F: FnOnce(String) + 'static,
+{
+    let reply_id = REPLY_ID_NEXT.fetch_add(1, Ordering::SeqCst);
+    REPLIES.with(|replies| replies.borrow_mut().insert(reply_id, (id, Box::new(f))));
+    let script = format!(
+        "window.ipc.postMessage(\"@reply {}\\n\" + String({}));",
+        reply_id, expression,
+    );
+    if !queue_script(id, script) {
+        REPLIES.with(|replies| replies.borrow_mut().remove(&reply_id));
+    }
+}
+
+/// Invoke the function waiting for a reply, if any.
+pub(crate) fn resolve_reply(reply_id: u64, body: String) {
+    let reply = REPLIES.with(|replies| replies.borrow_mut().remove(&reply_id));
+    if let Some((_, f)) = reply {
+        f(body);
+    }
+}
+
+/// Take the listeners of the handlers declared in the content of a WebView.
+///
+/// Remarks:
+/// * Drop them outside of any registry borrow, as dropping detaches them.
+fn unmount(id: ViewId) -> Vec<Listener> {
+    MOUNTED.with(|mounted| mounted.borrow_mut().remove(&id)).unwrap_or_default()
+}
+
+/// Send an event regarding a WebView to the runtime.
+///
+/// Remarks:
+/// * If the WebView is already destroyed, nothing is sent and `Error::StaleView` is raised.
+/// * Queued scripts are sent first, so the runtime sees every operation in order.
+fn send_view_event(id: ViewId, event: JoEvent) -> bool {
+    if !is_view_alive(id) {
+        raise_error(Error::StaleView(id));
+        return false;
+    }
+    flush_scripts();
+    PROXY.with(move |proxy| proxy.borrow().as_ref().unwrap()
+        .send_event(event).unwrap());
+    true
+}
+
 /// Handle to a WebView.
 ///
 /// Remarks:
@@ -46,78 +299,209 @@ fn remove_cur_view(id: usize) {
 ///     * This is enforced by the usage of a thread local static value of event loop proxy.
 /// * The behaviour is not the same as plain old Rust stuffs.
 ///     * It doesn't destroy the WebView when it is dropped.
-///     * In fact, the `acquire` function is used to gain access to a WebView through its index.
+///     * In fact, the `acquire` function is used to gain access to a WebView through its ID.
 ///     * You need to call `destroy` to dispose the WebView.
+/// * Using a handle after the WebView is destroyed raises `Error::StaleView` and does nothing.
 #[repr(transparent)]
 #[derive(Debug, Clone, PartialEq, Hash)]
 pub struct View {
-    ord: usize,
+    ord: ViewId,
 }
 
 impl View {
     /// Create a new WebView.
     pub fn new(spec: Spec) -> Self {
         let ord = next_view_id();
-        PROXY.with(move |static_proxy| {
-            static_proxy.borrow().as_ref().unwrap()
-                .send_event(JoEvent::CreateWebView {
-                    ord,
-                    spec,
-                }).unwrap();
+        send_view_event(ord, JoEvent::CreateWebView {
+            ord,
+            spec,
         });
-        add_cur_view(ord);
         Self { ord }
     }
 
-    /// Acquire an existing WebView by its index.
-    pub fn acquire(id: usize) -> Option<Self> {
-        VIEW_CUR.with(|cur| {
-            if cur.borrow().contains(&id) {
-                Some(Self { ord: id })
-            } else {
-                None
-            }
-        })
+    /// Create a new WebView with the default Spec of the application.
+    pub fn new_default() -> Self {
+        Self::new(DEFAULT_SPEC.with(|spec| spec.borrow().clone()))
+    }
+
+    /// Acquire an existing WebView by its ID.
+    ///
+    /// Remarks:
+    /// * It returns `None` if the WebView has been destroyed.
+    pub fn acquire(id: ViewId) -> Option<Self> {
+        if is_view_alive(id) {
+            Some(Self { ord: id })
+        } else {
+            None
+        }
+    }
+
+    /// Check whether the WebView is still alive.
+    pub fn is_alive(&self) -> bool {
+        is_view_alive(self.ord)
     }
 
     /// Evaluate arbitrary JavaScript code in the WebView.
     ///
     /// Remarks:
     /// * Safety concern: You need to know what you are doing.
+    /// * The code is evaluated on its own rather than coalesced, after the scripts queued before it.
     pub fn eval(&self, script: String) {
-        PROXY.with(move |static_proxy| {
-            static_proxy.borrow().as_ref().unwrap()
-                .send_event(JoEvent::EvalScript {
-                    ord: self.ord,
-                    script
-                }).unwrap();
+        send_view_event(self.ord, JoEvent::EvalScript {
+            ord: self.ord,
+            script
+        });
+    }
+
+    /// Invoke a callback once every script previously sent to the WebView has run.
+    ///
+    /// Remarks:
+    /// * It makes a round-trip through the page, so the DOM reflects all prior operations.
+    /// * The callback is dropped without being invoked if the WebView is destroyed first.
+    pub fn flushed<F>(&self, callback: F)
+        where
+            F: FnOnce(View) + 'static,
+    {
+        let ord = self.ord;
+        request_reply(ord, "\"\"", move |_| {
+            if let Some(view) = View::acquire(ord) {
+                callback(view);
+            }
+        });
+    }
+
+    /// Block until every script previously sent to the WebView has run.
+    ///
+    /// Remarks:
+    /// * Other tasks of user thread, like event callbacks, keep being processed while waiting.
+    /// * Returns `false` if the WebView is destroyed or the runtime starts exiting while waiting.
+    pub fn flush(&self) -> bool {
+        self.wait_reply("\"\"").is_some()
+    }
+
+    /// Write the current DOM of the WebView into a standalone HTML file.
+    ///
+    /// Remarks:
+    /// * It blocks like `flush`, so the file reflects every operation sent before.
+    /// * The Joestar bridge and the `data-jo` handles are left out, the file opens in any browser.
+    /// * Fails if the WebView is destroyed or the runtime starts exiting while waiting.
+    pub fn export_html<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let markup = self.wait_reply("_lk_export()").ok_or_else(|| {
+            io::Error::other(format!("view {} is gone", self.ord))
+        })?;
+        fs::write(path, markup)
+    }
+
+    /// Send an expression to the page and block until its string value is replied.
+    ///
+    /// Remarks:
+    /// * Returns `None` if the WebView is destroyed or the runtime starts exiting while waiting.
+    fn wait_reply(&self, expression: &str) -> Option<String> {
+        let reply = Rc::new(RefCell::new(None));
+        let reply_in_callback = reply.clone();
+        request_reply(self.ord, expression, move |body| {
+            *reply_in_callback.borrow_mut() = Some(body);
         });
+        flush_scripts();
+        loop {
+            if let Some(body) = reply.borrow_mut().take() {
+                return Some(body);
+            }
+            if !self.is_alive() || !pump_user_task() {
+                return None;
+            }
+        }
+    }
+
+    /// Run a closure, sending the scripts it queues as one evaluation per WebView when it returns.
+    ///
+    /// Remarks:
+    /// * Scripts are already coalesced per task of user thread, a batch extends this to its scope.
+    ///     * E.g. tasks run by `View::flush` inside the scope don't send the scripts early.
+    /// * Sending an event that must be ordered with the scripts, like destroying a View, still flushes them.
+    /// * Batches may be nested, the scripts are sent when the outermost one returns.
+    pub fn batch<F, R>(f: F) -> R
+        where
+            F: FnOnce() -> R,
+    {
+        struct BatchGuard;
+        impl Drop for BatchGuard {
+            fn drop(&mut self) {
+                let depth = BATCH_DEPTH.with(|depth| {
+                    depth.set(depth.get() - 1);
+                    depth.get()
+                });
+                if depth == 0 {
+                    flush_scripts();
+                }
+            }
+        }
+        BATCH_DEPTH.with(|depth| depth.set(depth.get() + 1));
+        let _guard = BatchGuard;
+        f()
     }
 
     /// Destroy the WebView.
+    ///
+    /// Remarks:
+    /// * All callbacks bound to the WebView and its elements are removed.
     pub fn destroy(self) {
-        PROXY.with(move |static_proxy| {
-            static_proxy.borrow().as_ref().unwrap()
-                .send_event(JoEvent::DestroyWebView {
-                    ord: self.ord,
-                }).unwrap();
-        });
-        remove_cur_view(self.ord);
+        if send_view_event(self.ord, JoEvent::DestroyWebView {
+            ord: self.ord,
+        }) {
+            forget_view(self.ord);
+        }
     }
 
     /// Fill an element as the root node of content.
-    pub fn fill(&self, model: Model) {
-        PROXY.with(move |static_proxy| {
-            static_proxy.borrow().as_ref().unwrap()
-                .send_event(JoEvent::EvalScript {
-                    ord: self.ord,
-                    script: format!("document.body.innerHTML = `{}`;", html_string(&model))
-                }).unwrap();
-        });
+    ///
+    /// Remarks:
+    /// * Handlers declared with `Model::on` are bound in the same script, so no event is missed.
+    ///     * Elements with handlers but no ID are given an automatic one, unless stamped with a handle.
+    /// * Handlers of the previous content are unbound.
+    /// * With `JoestarBuilder::stamp_handles`, every element is stamped with a `data-jo` handle.
+    ///     * Agents passed to the handlers then stay bound to their elements, see `Agent::pin`.
+    /// * Very large content is sent to the page in several scripts and assembled there.
+    pub fn fill(&self, mut model: Model) {
+        if STAMP_HANDLES.with(Cell::get) {
+            model.stamp_handles();
+        }
+        let mut handlers = Vec::new();
+        model.take_handlers(&mut handlers);
+        drop(unmount(self.ord));
+        let markup = model.to_html();
+        let mut script = String::with_capacity(markup.len() + 64);
+        if markup.len() <= FILL_CHUNK_LEN {
+            script.push_str("document.body.innerHTML = ");
+            write_js_string(&mut script, &markup);
+        } else {
+            for chunk_script in chunk_scripts(&markup, FILL_CHUNK_LEN) {
+                if !send_view_event(self.ord, JoEvent::EvalScript { ord: self.ord, script: chunk_script }) {
+                    return;
+                }
+            }
+            script.push_str("document.body.innerHTML = _lk_take_chunks()");
+        }
+        script.push_str(";_lk_scan(document.body);");
+        let mut listeners = Vec::with_capacity(handlers.len());
+        for (position, handler) in handlers {
+            let func = handler.func;
+            let agent = Agent { ord: self.ord, position };
+            let (listener, bind_script) = agent.bind_script(
+                &handler.key,
+                handler.options,
+                move |agent, detail| (func.borrow_mut())(agent, detail),
+            );
+            script.push_str(&bind_script);
+            listeners.push(listener);
+        }
+        if queue_script(self.ord, script) {
+            MOUNTED.with(|mounted| mounted.borrow_mut().insert(self.ord, listeners));
+        }
     }
 
-    /// Get the index of the WebView.
-    pub fn ord(&self) -> usize {
+    /// Get the ID of the WebView.
+    pub fn id(&self) -> ViewId {
         self.ord
     }
 
@@ -137,23 +521,51 @@ impl View {
         }
     }
 
+    /// Get the agent to an element by its `data-jo` handle.
+    ///
+    /// Remarks:
+    /// * The agent stays bound to the element when others are inserted or removed around it.
+    pub fn at_handle(&self, handle: u64) -> Agent {
+        Agent {
+            ord: self.ord,
+            position: Position::HandlePath(handle, vec![]),
+        }
+    }
+
+    /// Get the agent to the element filled from a Model.
+    ///
+    /// Remarks:
+    /// * The element is located by its handle if stamped, or else by its ID.
+    /// * Elements with neither can't be told apart from others, so `None` is returned.
+    pub fn agent_of(&self, model: &Model) -> Option<Agent> {
+        if let Some(handle) = model.handle() {
+            Some(self.at_handle(handle))
+        } else {
+            model.get_id().map(|id| self.lookup(id))
+        }
+    }
+
     /// Bind an callback to a View event.
     ///
     /// Remarks:
     /// * The callback is unique regarding to the event key.
     ///     * If the callback is already bound, it is replaced.
-    /// * The callback is called with the agent to the element and the detail of the event.
-    pub fn bind<F>(&self, key: ViewEventKey, callback: F) -> Callback
+    /// * The callback is called with the View and the detail of the event.
+    /// * A callback bound to `CloseRequest` takes over closing, the View is not destroyed for it.
+    pub fn bind<F>(&self, key: ViewEventKey, mut callback: F) -> Callback
         where
-            F: FnMut(Agent, HashMap<String, String>) + 'static,
+            F: FnMut(View, HashMap<String, String>) + 'static,
     {
-        let callback = Callback::create(callback);
-        PROXY.with(|proxy| proxy.borrow_mut().as_ref().unwrap()
-            .send_event(JoEvent::RegisterEvent {
-                ord: self.ord,
-                key,
-                cb_index: callback.id,
-            }).unwrap());
+        let callback = Callback::create_owned(self.ord, move |agent, detail| {
+            if let Some(view) = agent.view() {
+                callback(view, detail);
+            }
+        });
+        send_view_event(self.ord, JoEvent::RegisterEvent {
+            ord: self.ord,
+            key,
+            cb_index: callback.id,
+        });
         callback
     }
 }
@@ -163,6 +575,7 @@ pub enum ViewEventKey {
     CloseRequest,
     Resize,
     Move,
+    Ready,
 }
 
 pub type WrappedCallback = Box<dyn FnMut(String, HashMap<String, String>)>;
@@ -170,183 +583,928 @@ pub type WrappedCallback = Box<dyn FnMut(String, HashMap<String, String>)>;
 /// Model of a DOM element.
 ///
 /// Remarks:
-/// * All of the values are unchecked and not escaped, so be careful.
+/// * Text and attribute values are escaped when serialized, except the text of `script` and `style`.
+/// * Tags, attribute names and styles are unchecked and not escaped, so be careful.
+/// * Void elements like `input` are serialized without children or an end tag.
 #[derive(Debug, Clone, PartialEq)]
 pub struct Model {
     tag: String,
     id: Option<String>,
-    attrs: HashMap<String, String>,
-    style: HashMap<String, String>,
-    text: Option<String>,
-    children: Vec<Model>,
+    attrs: Vec<(String, Option<String>)>,
+    style: Vec<(String, String)>,
+    children: Vec<Node>,
+    handlers: Vec<ModelHandler>,
 }
 
-impl Model {
-    /// Create a new Model.
-    pub fn new<S: Into<String>>(tag: S) -> Self {
-        Self {
-            tag: tag.into(),
-            id: None,
-            attrs: Default::default(),
-            style: Default::default(),
-            text: None,
-            children: vec![],
-        }
-    }
+/// Child node of a Model.
+///
+/// Variants:
+/// * Element: A child element.
+/// * Text: A text node.
+/// * Comment: A comment.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Element(Model),
+    Text(String),
+    Comment(String),
+}
 
-    /// Set the ID of the element.
-    ///
-    /// Remarks:
-    /// * It does not check the correctness of the ID.
-    pub fn id<S: Into<String>>(mut self, id: S) -> Self {
-        self.id = Some(id.into());
-        self
+impl From<Model> for Node {
+    fn from(model: Model) -> Self {
+        Node::Element(model)
     }
+}
 
-    /// Set an attribute of the element.
-    ///
-    /// Remarks:
-    /// * It does not check the correctness of the attribute.
-    /// * It does not reject `style` or `id` attributes.
-    pub fn attr<S1: Into<String>, S2: Into<String>>(mut self, key: S1, val: S2) -> Self {
-        self.attrs.insert(key.into(), val.into());
-        self
+impl From<String> for Node {
+    fn from(text: String) -> Self {
+        Node::Text(text)
     }
+}
 
-    /// Set a style of the element.
-    ///
-    /// Remarks:
-    /// * It does not check the correctness of the style.
-    pub fn style<S1: Into<String>, S2: Into<String>>(mut self, key: S1, val: S2) -> Self {
-        self.style.insert(key.into(), val.into());
-        self
+impl From<&str> for Node {
+    fn from(text: &str) -> Self {
+        Node::Text(text.to_string())
     }
+}
 
-    /// Add a child element.
-    pub fn child(mut self, child: Model) -> Self {
-        self.children.push(child);
-        self
-    }
+/// Something that can be added to the children of a Model.
+///
+/// Remarks:
+/// * Used by the `html!` macro for embedded expressions.
+/// * Strings and numbers become text nodes, `Option`s and `Vec`s add their content.
+pub trait ModelChild {
+    /// Add to the children of the parent and give it back.
+    fn add_to(self, parent: Model) -> Model;
+}
 
-    /// Add child elements.
-    pub fn children(mut self, children: Vec<Model>) -> Self {
-        self.children.extend(children);
-        self
+impl ModelChild for Model {
+    fn add_to(self, parent: Model) -> Model {
+        parent.child(self)
     }
+}
 
-    /// Set the text content of the element.
-    pub fn text<S: Into<String>>(mut self, text: S) -> Self {
-        self.text = Some(text.into());
-        self
+impl ModelChild for Node {
+    fn add_to(self, parent: Model) -> Model {
+        parent.node(self)
     }
+}
 
-    fn export_markup(&self) -> String {
-        html_string(self)
+impl ModelChild for String {
+    fn add_to(self, parent: Model) -> Model {
+        parent.text(self)
     }
 }
 
-fn attrs_string(attrs: &HashMap<String, String>) -> String {
-    let mut attrs_string = String::new();
-    for (key, val) in attrs {
-        attrs_string.push_str(&format!("{}=\"{}\" ", key, val));
+impl ModelChild for &str {
+    fn add_to(self, parent: Model) -> Model {
+        parent.text(self)
     }
-    attrs_string
 }
 
-fn style_string(style: &HashMap<String, String>) -> String {
-    let mut style_string = String::new();
-    for (key, val) in style {
-        style_string.push_str(&format!("{}: {}; ", key, val));
+impl ModelChild for &String {
+    fn add_to(self, parent: Model) -> Model {
+        parent.text(self.as_str())
     }
-    style_string
 }
 
-fn html_string(model: &Model) -> String {
-    let mut result = String::new();
-    result.push_str(&format!("<{}", model.tag));
-    if let Some(id) = &model.id {
-        result.push_str(&format!(" id=\"{}\"", id));
-    }
-    if !model.attrs.is_empty() {
-        result.push_str(&format!(" {}", attrs_string(&model.attrs)));
-    }
-    if !model.style.is_empty() {
-        result.push_str(&format!(" style=\"{}\"", style_string(&model.style)));
-    }
-    result.push_str(">");
-    if let Some(text) = &model.text {
-        result.push_str(&text);
+impl<T: ModelChild> ModelChild for Option<T> {
+    fn add_to(self, parent: Model) -> Model {
+        match self {
+            Some(child) => child.add_to(parent),
+            None => parent,
+        }
     }
-    for child in &model.children {
-        result.push_str(&html_string(child));
+}
+
+impl<T: ModelChild> ModelChild for Vec<T> {
+    fn add_to(self, parent: Model) -> Model {
+        self.into_iter().fold(parent, |parent, child| child.add_to(parent))
     }
-    result.push_str(&format!("</{}>", model.tag));
-    result
 }
 
-fn invoke_callback(index: usize, path: &str, detail: HashMap<String, String>) {
-    let callback = unsafe { CALLBACKS.get_mut(&index).unwrap() };
-    callback(Agent::from(path), detail)
+macro_rules! impl_model_child_display {
+    ($($t:ty),*) => {
+        $(
+            impl ModelChild for $t {
+                fn add_to(self, parent: Model) -> Model {
+                    parent.text(self.to_string())
+                }
+            }
+        )*
+    };
 }
 
-/// Position of an element.
+impl_model_child_display!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize, f32, f64, char);
+
+/// Value of an attribute given to `Model::attr`.
 ///
 /// Variants:
-/// * Path: The path from the root node of content.
-/// * IdPath: The path from the element with the given ID.
+/// * Value: The attribute with a value.
+/// * Present: The attribute without a value, like `disabled`.
+/// * Absent: No attribute, removing it if it was set.
 ///
 /// Remarks:
-/// * The path is a sequence of indices of children.
-/// * The path is empty for the root node of content or the element with the given ID.
-#[derive(Debug, Clone)]
-pub enum Position {
-    Path(Vec<usize>),
-    IdPath(String, Vec<usize>),
+/// * Strings convert to `Value`, `true` to `Present` and `false` to `Absent`.
+/// * `Some` converts like its content and `None` to `Absent`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AttrValue {
+    Value(String),
+    Present,
+    Absent,
 }
 
-/// Agent to an element.
-///
-/// Remarks:
-/// * The agent doesn't directly hold the element.
-/// * It doesn't check the correctness of the path or ID.
-#[derive(Debug, Clone)]
-pub struct Agent {
-    ord: usize,
-    position: Position,
+impl From<&str> for AttrValue {
+    fn from(value: &str) -> Self {
+        AttrValue::Value(value.to_string())
+    }
 }
 
-impl Agent {
-    pub(crate) fn invalid() -> Self {
-        Self {
-            ord: usize::MAX,
-            position: Position::Path(vec![]),
-        }
+impl From<&String> for AttrValue {
+    fn from(value: &String) -> Self {
+        AttrValue::Value(value.clone())
     }
+}
 
-    fn script_get_element(&self) -> String {
-        match &self.position {
-            Position::Path(path) => {
-                let mut script = String::new();
-                script.push_str(&format!("document.body.children[0]"));
-                for i in path {
-                    script.push_str(&format!(".children[{}]", i));
-                }
-                script
-            }
-            Position::IdPath(id, path) => {
-                let mut script = String::new();
-                script.push_str(&format!("document.getElementById(\"{}\")", id));
-                for i in path {
-                    script.push_str(&format!(".children[{}]", i));
-                }
-                script
-            }
-        }
+impl From<String> for AttrValue {
+    fn from(value: String) -> Self {
+        AttrValue::Value(value)
     }
+}
 
-    fn script_set_element(&self, target: &str) -> String {
-        let get_script = self.script_get_element();
-        format!("{} = {}", get_script, target)
+impl From<bool> for AttrValue {
+    fn from(present: bool) -> Self {
+        if present { AttrValue::Present } else { AttrValue::Absent }
+    }
+}
+
+impl<T: Into<AttrValue>> From<Option<T>> for AttrValue {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(AttrValue::Absent, Into::into)
+    }
+}
+
+/// Elements that have no content and no end tag.
+const VOID_ELEMENTS: [&str; 13] = [
+    "area", "base", "br", "col", "embed", "hr", "img",
+    "input", "link", "meta", "source", "track", "wbr",
+];
+
+/// Check whether a tag names a void element.
+pub(crate) fn is_void_element(tag: &str) -> bool {
+    VOID_ELEMENTS.iter().any(|void| void.eq_ignore_ascii_case(tag))
+}
+
+type HandlerFunc = Rc<RefCell<dyn FnMut(Agent, HashMap<String, String>)>>;
+
+/// Handler of a DOM event declared in a Model.
+#[derive(Clone)]
+struct ModelHandler {
+    key: String,
+    options: BindOptions,
+    func: HandlerFunc,
+}
+
+impl Debug for ModelHandler {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ModelHandler")
+            .field("key", &self.key)
+            .field("options", &self.options)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for ModelHandler {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+            && self.options == other.options
+            && Rc::ptr_eq(&self.func, &other.func)
+    }
+}
+
+static AUTO_ID_NEXT: AtomicUsize = AtomicUsize::new(0);
+
+impl Model {
+    /// Create a new Model.
+    pub fn new<S: Into<String>>(tag: S) -> Self {
+        Self {
+            tag: tag.into(),
+            id: None,
+            attrs: Default::default(),
+            style: Default::default(),
+            children: vec![],
+            handlers: vec![],
+        }
+    }
+
+    /// Declare a handler of a DOM event on the element.
+    ///
+    /// Remarks:
+    /// * The handler is bound when the Model is filled into a View, see `View::fill`.
+    /// * The handler is unbound when the content is replaced or the View is destroyed.
+    pub fn on<S, F>(self, key: S, handler: F) -> Self
+        where
+            S: Into<String>,
+            F: FnMut(Agent, HashMap<String, String>) + 'static,
+    {
+        self.on_with(key, BindOptions::default(), handler)
+    }
+
+    /// Declare a handler of a DOM event on the element with options.
+    ///
+    /// Remarks:
+    /// * See `on` and `BindOptions`.
+    pub fn on_with<S, F>(mut self, key: S, options: BindOptions, handler: F) -> Self
+        where
+            S: Into<String>,
+            F: FnMut(Agent, HashMap<String, String>) + 'static,
+    {
+        self.handlers.push(ModelHandler {
+            key: key.into(),
+            options,
+            func: Rc::new(RefCell::new(handler)),
+        });
+        self
+    }
+
+    /// Move the handlers of the tree out along with the positions of their elements.
+    fn take_handlers(&mut self, out: &mut Vec<(Position, ModelHandler)>) {
+        if !self.handlers.is_empty() {
+            let position = match self.handle() {
+                Some(handle) => Position::HandlePath(handle, vec![]),
+                None => {
+                    let id = self.id.get_or_insert_with(|| {
+                        format!("jo-auto-{}", AUTO_ID_NEXT.fetch_add(1, Ordering::SeqCst))
+                    });
+                    Position::IdPath(id.clone(), vec![])
+                }
+            };
+            for handler in self.handlers.drain(..) {
+                out.push((position.clone(), handler));
+            }
+        }
+        for child in &mut self.children {
+            if let Node::Element(child) = child {
+                child.take_handlers(out);
+            }
+        }
+    }
+
+    /// Stamp every element of the tree with a fresh `data-jo` handle, unless it already has one.
+    ///
+    /// Remarks:
+    /// * Stamp before filling to keep the handles, see `handle` and `View::agent_of`.
+    /// * `View::fill` does this by itself with `JoestarBuilder::stamp_handles`.
+    pub fn stamp_handles(&mut self) {
+        if self.handle().is_none() {
+            set_entry(&mut self.attrs, "data-jo".to_string(), Some(next_handle().to_string()));
+        }
+        for child in &mut self.children {
+            if let Node::Element(child) = child {
+                child.stamp_handles();
+            }
+        }
+    }
+
+    /// Set the ID of the element.
+    ///
+    /// Remarks:
+    /// * It does not check the correctness of the ID.
+    pub fn id<S: Into<String>>(mut self, id: S) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Set an attribute of the element.
+    ///
+    /// Remarks:
+    /// * It does not check the correctness of the attribute.
+    /// * It does not reject `style` or `id` attributes.
+    /// * Attributes are serialized in the order they are first set.
+    /// * Pass `true` for a boolean attribute, and `false` or `None` to remove the attribute.
+    pub fn attr<S: Into<String>, V: Into<AttrValue>>(mut self, key: S, val: V) -> Self {
+        let key = key.into();
+        match val.into() {
+            AttrValue::Value(val) => set_entry(&mut self.attrs, key, Some(val)),
+            AttrValue::Present => set_entry(&mut self.attrs, key, None),
+            AttrValue::Absent => self.attrs.retain(|(k, _)| *k != key),
+        }
+        self
+    }
+
+    /// Set a style of the element.
+    ///
+    /// Remarks:
+    /// * It does not check the correctness of the style.
+    /// * Styles are serialized in the order they are first set.
+    pub fn style<S1: Into<String>, S2: Into<String>>(mut self, key: S1, val: S2) -> Self {
+        set_entry(&mut self.style, key.into(), val.into());
+        self
+    }
+
+    /// Add a child element.
+    pub fn child(mut self, child: Model) -> Self {
+        self.children.push(Node::Element(child));
+        self
+    }
+
+    /// Add child elements.
+    pub fn children(mut self, children: Vec<Model>) -> Self {
+        self.children.extend(children.into_iter().map(Node::Element));
+        self
+    }
+
+    /// Add a child node.
+    pub fn node<N: Into<Node>>(mut self, node: N) -> Self {
+        self.children.push(node.into());
+        self
+    }
+
+    /// Add a text node.
+    ///
+    /// Remarks:
+    /// * Text nodes and child elements are kept in order, e.g. `Hello <b>world</b>!`.
+    /// * The text is escaped, it is never interpreted as markup.
+    pub fn text<S: Into<String>>(mut self, text: S) -> Self {
+        self.children.push(Node::Text(text.into()));
+        self
+    }
+
+    /// Add a comment.
+    ///
+    /// Remarks:
+    /// * Sequences that would end the comment early are broken up.
+    pub fn comment<S: Into<String>>(mut self, comment: S) -> Self {
+        self.children.push(Node::Comment(comment.into()));
+        self
+    }
+
+    /// Get the tag of the element.
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// Get the ID of the element.
+    pub fn get_id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// Get the `data-jo` handle the element is stamped with, see `stamp_handles`.
+    pub fn handle(&self) -> Option<u64> {
+        get_entry(&self.attrs, "data-jo")
+            .and_then(|h| h.as_deref())
+            .and_then(|h| h.parse().ok())
+    }
+
+    /// Set or clear the ID of the element in place.
+    pub fn set_id<S: Into<String>>(&mut self, id: Option<S>) {
+        self.id = id.map(Into::into);
+    }
+
+    /// Get the value of an attribute.
+    ///
+    /// Remarks:
+    /// * An attribute without a value gives an empty string, like `getAttribute` does.
+    pub fn get_attr(&self, key: &str) -> Option<&str> {
+        get_entry(&self.attrs, key).map(|val| val.as_deref().unwrap_or(""))
+    }
+
+    /// Iterate over the attributes in order, with `None` for attributes without a value.
+    pub fn attrs(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.attrs.iter().map(|(key, val)| (key.as_str(), val.as_deref()))
+    }
+
+    /// Set an attribute of the element in place.
+    ///
+    /// Remarks:
+    /// * See `attr` for the accepted values.
+    pub fn set_attr<S: Into<String>, V: Into<AttrValue>>(&mut self, key: S, val: V) {
+        let key = key.into();
+        match val.into() {
+            AttrValue::Value(val) => set_entry(&mut self.attrs, key, Some(val)),
+            AttrValue::Present => set_entry(&mut self.attrs, key, None),
+            AttrValue::Absent => {
+                self.remove_attr(&key);
+            }
+        }
+    }
+
+    /// Remove an attribute of the element, giving it back if it was set.
+    pub fn remove_attr(&mut self, key: &str) -> Option<AttrValue> {
+        let index = self.attrs.iter().position(|(k, _)| k == key)?;
+        Some(match self.attrs.remove(index).1 {
+            Some(val) => AttrValue::Value(val),
+            None => AttrValue::Present,
+        })
+    }
+
+    /// Get the value of a style.
+    pub fn get_style(&self, key: &str) -> Option<&str> {
+        get_entry(&self.style, key).map(String::as_str)
+    }
+
+    /// Iterate over the styles in order.
+    pub fn styles(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.style.iter().map(|(key, val)| (key.as_str(), val.as_str()))
+    }
+
+    /// Set a style of the element in place.
+    pub fn set_style<S1: Into<String>, S2: Into<String>>(&mut self, key: S1, val: S2) {
+        set_entry(&mut self.style, key.into(), val.into());
+    }
+
+    /// Remove a style of the element, giving it back if it was set.
+    pub fn remove_style(&mut self, key: &str) -> Option<String> {
+        let index = self.style.iter().position(|(k, _)| k == key)?;
+        Some(self.style.remove(index).1)
+    }
+
+    /// Get the child nodes, including text nodes and comments.
+    ///
+    /// Remarks:
+    /// * Named apart from the `children` builder method.
+    pub fn child_nodes(&self) -> &[Node] {
+        &self.children
+    }
+
+    /// Get the child nodes for changing them in place.
+    pub fn child_nodes_mut(&mut self) -> &mut Vec<Node> {
+        &mut self.children
+    }
+
+    /// Iterate over the child elements, skipping text nodes and comments.
+    pub fn child_elements(&self) -> impl DoubleEndedIterator<Item = &Model> {
+        self.children.iter().filter_map(|child| match child {
+            Node::Element(model) => Some(model),
+            _ => None,
+        })
+    }
+
+    /// Iterate over the child elements for changing them in place.
+    pub fn child_elements_mut(&mut self) -> impl DoubleEndedIterator<Item = &mut Model> {
+        self.children.iter_mut().filter_map(|child| match child {
+            Node::Element(model) => Some(model),
+            _ => None,
+        })
+    }
+
+    /// Iterate over the element and all of its descendant elements, depth-first in document order.
+    pub fn walk(&self) -> Walk<'_> {
+        Walk { stack: vec![self] }
+    }
+
+    /// Visit the element and all of its descendant elements in place, depth-first in document order.
+    ///
+    /// Remarks:
+    /// * The children of an element are visited after it, so changes made to them are seen.
+    pub fn walk_mut<F: FnMut(&mut Model)>(&mut self, mut visitor: F) {
+        fn visit<F: FnMut(&mut Model)>(model: &mut Model, visitor: &mut F) {
+            visitor(model);
+            for child in model.child_elements_mut() {
+                visit(child, visitor);
+            }
+        }
+        visit(self, &mut visitor)
+    }
+
+    /// Find the first element with the given ID in the tree, including the element itself.
+    pub fn find_by_id(&self, id: &str) -> Option<&Model> {
+        self.walk().find(|model| model.id.as_deref() == Some(id))
+    }
+
+    /// Find the first element with the given ID in the tree for changing it in place.
+    pub fn find_by_id_mut(&mut self, id: &str) -> Option<&mut Model> {
+        if self.id.as_deref() == Some(id) {
+            return Some(self);
+        }
+        self.child_elements_mut().find_map(|child| child.find_by_id_mut(id))
+    }
+
+    /// Find all elements of the tree matching a predicate, in document order.
+    pub fn find_all<P: FnMut(&Model) -> bool>(&self, mut predicate: P) -> Vec<&Model> {
+        self.walk().filter(|model| predicate(model)).collect()
+    }
+
+    /// Find the elements of the tree matching a predicate for changing them in place.
+    ///
+    /// Remarks:
+    /// * Only the outermost matches are returned, the descendants of a match are not searched.
+    ///     * Use `walk_mut` to reach every element instead.
+    pub fn find_all_mut<P: FnMut(&Model) -> bool>(&mut self, mut predicate: P) -> Vec<&mut Model> {
+        fn search<'a, P: FnMut(&Model) -> bool>(
+            model: &'a mut Model,
+            predicate: &mut P,
+            found: &mut Vec<&'a mut Model>,
+        ) {
+            if predicate(model) {
+                found.push(model);
+                return;
+            }
+            for child in model.child_elements_mut() {
+                search(child, predicate, found);
+            }
+        }
+        let mut found = vec![];
+        search(self, &mut predicate, &mut found);
+        found
+    }
+
+    /// Write the markup of the tree.
+    ///
+    /// Remarks:
+    /// * Everything is written straight into `out`, no intermediate string is built per node.
+    pub(crate) fn write_html<W: Write>(&self, out: &mut W) -> std::fmt::Result {
+        self.write_start_tag(out)?;
+        if is_void_element(&self.tag) {
+            return Ok(());
+        }
+        self.write_children(out)?;
+        write!(out, "</{}>", self.tag)
+    }
+
+    /// Write the child nodes, leaving the text of `script` and `style` elements unescaped.
+    fn write_children<W: Write>(&self, out: &mut W) -> std::fmt::Result {
+        let raw_text = self.tag.eq_ignore_ascii_case("script") || self.tag.eq_ignore_ascii_case("style");
+        for child in &self.children {
+            match child {
+                Node::Text(text) if raw_text => out.write_str(text)?,
+                child => child.write_html(out)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the markup of the tree with one node per line, indented by depth.
+    ///
+    /// Remarks:
+    /// * Elements containing only text are kept on a single line.
+    /// * Text nodes made only of whitespace are skipped.
+    fn write_html_pretty<W: Write>(&self, out: &mut W, depth: usize) -> std::fmt::Result {
+        write_indent(out, depth)?;
+        self.write_start_tag(out)?;
+        if is_void_element(&self.tag) {
+            return out.write_char('\n');
+        }
+        if self.children.iter().all(|child| matches!(child, Node::Text(_))) {
+            self.write_children(out)?;
+        } else {
+            out.write_char('\n')?;
+            for child in &self.children {
+                match child {
+                    Node::Element(model) => model.write_html_pretty(out, depth + 1)?,
+                    Node::Text(text) if text.trim().is_empty() => {}
+                    Node::Text(_) | Node::Comment(_) => {
+                        write_indent(out, depth + 1)?;
+                        child.write_html(out)?;
+                        out.write_char('\n')?;
+                    }
+                }
+            }
+            write_indent(out, depth)?;
+        }
+        writeln!(out, "</{}>", self.tag)
+    }
+
+    fn write_start_tag<W: Write>(&self, out: &mut W) -> std::fmt::Result {
+        write!(out, "<{}", self.tag)?;
+        if let Some(id) = &self.id {
+            out.write_str(" id=\"")?;
+            write_escaped(out, id, true)?;
+            out.write_char('"')?;
+        }
+        for (key, val) in &self.attrs {
+            write!(out, " {}", key)?;
+            if let Some(val) = val {
+                out.write_str("=\"")?;
+                write_escaped(out, val, true)?;
+                out.write_char('"')?;
+            }
+        }
+        if !self.style.is_empty() {
+            out.write_str(" style=\"")?;
+            for (key, val) in &self.style {
+                write_escaped(out, key, true)?;
+                out.write_str(": ")?;
+                write_escaped(out, val, true)?;
+                out.write_str("; ")?;
+            }
+            out.write_char('"')?;
+        }
+        out.write_char('>')
+    }
+
+    /// Estimate the length of the markup of the tree, used to size the buffer.
+    fn estimate_html_len(&self) -> usize {
+        let mut len = self.tag.len() * 2 + 5;
+        len += self.id.as_ref().map_or(0, |id| id.len() + 6);
+        len += self.attrs.iter()
+            .map(|(key, val)| key.len() + val.as_ref().map_or(1, |val| val.len() + 4))
+            .sum::<usize>();
+        if !self.style.is_empty() {
+            len += 9 + self.style.iter().map(|(key, val)| key.len() + val.len() + 4).sum::<usize>();
+        }
+        len + self.children.iter().map(Node::estimate_html_len).sum::<usize>()
+    }
+
+    /// Serialize the tree into HTML.
+    ///
+    /// Remarks:
+    /// * The output is stable, attributes and styles keep the order they were set in.
+    /// * Handlers declared with `on` are not part of the markup.
+    pub fn to_html(&self) -> String {
+        let mut markup = String::with_capacity(self.estimate_html_len());
+        self.write_html(&mut markup).unwrap();
+        markup
+    }
+
+    /// Render the tree as the body of a complete HTML5 document.
+    ///
+    /// Remarks:
+    /// * The document doesn't depend on Joestar, it opens in any browser.
+    /// * See `DocumentOptions` for the head of the document.
+    pub fn render_document(&self, options: &DocumentOptions) -> String {
+        let css_len = options.stylesheets.iter().map(String::len).sum::<usize>();
+        let mut document = String::with_capacity(self.estimate_html_len() + css_len + 256);
+        options.write_document(&mut document, self).unwrap();
+        document
+    }
+
+    /// Serialize the tree into HTML indented by two spaces per level, ending with a newline.
+    ///
+    /// Remarks:
+    /// * Meant for snapshots and diffs, the added whitespace may change how text renders.
+    /// * Elements containing only text are kept on a single line.
+    pub fn to_html_pretty(&self) -> String {
+        let mut markup = String::with_capacity(self.estimate_html_len() * 2);
+        self.write_html_pretty(&mut markup, 0).unwrap();
+        markup
+    }
+}
+
+/// Iterator over the elements of a tree, see `Model::walk`.
+#[derive(Debug, Clone)]
+pub struct Walk<'a> {
+    stack: Vec<&'a Model>,
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = &'a Model;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let model = self.stack.pop()?;
+        self.stack.extend(model.child_elements().rev());
+        Some(model)
+    }
+}
+
+impl Node {
+    fn write_html<W: Write>(&self, out: &mut W) -> std::fmt::Result {
+        match self {
+            Node::Element(model) => model.write_html(out),
+            Node::Text(text) => write_escaped(out, text, false),
+            Node::Comment(comment) => {
+                out.write_str("<!--")?;
+                let mut rest = comment.as_str();
+                if rest.starts_with('>') || rest.starts_with("->") {
+                    out.write_char(' ')?;
+                }
+                while let Some(index) = rest.find("--") {
+                    out.write_str(&rest[..index + 1])?;
+                    out.write_char(' ')?;
+                    rest = &rest[index + 1..];
+                }
+                out.write_str(rest)?;
+                if rest.ends_with('-') {
+                    out.write_char(' ')?;
+                }
+                out.write_str("-->")
+            }
+        }
+    }
+
+    fn estimate_html_len(&self) -> usize {
+        match self {
+            Node::Element(model) => model.estimate_html_len(),
+            Node::Text(text) => text.len(),
+            Node::Comment(comment) => comment.len() + 7,
+        }
+    }
+}
+
+/// Options of a document rendered by `Model::render_document`.
+///
+/// Remarks:
+/// * The charset defaults to `utf-8`, other options default to empty.
+/// * Stylesheets are inlined in `style` elements, in the order they are added.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DocumentOptions {
+    title: String,
+    lang: Option<String>,
+    charset: String,
+    stylesheets: Vec<String>,
+}
+
+impl Default for DocumentOptions {
+    fn default() -> Self {
+        Self {
+            title: String::new(),
+            lang: None,
+            charset: "utf-8".to_string(),
+            stylesheets: vec![],
+        }
+    }
+}
+
+impl DocumentOptions {
+    /// Create options with the defaults.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the title of the document.
+    pub fn title<S: Into<String>>(mut self, title: S) -> Self {
+        self.title = title.into();
+        self
+    }
+
+    /// Set the language of the document, e.g. `en`.
+    pub fn lang<S: Into<String>>(mut self, lang: S) -> Self {
+        self.lang = Some(lang.into());
+        self
+    }
+
+    /// Set the charset declared by the document.
+    ///
+    /// Remarks:
+    /// * The rendered string is always UTF-8, change it only if the file is re-encoded.
+    pub fn charset<S: Into<String>>(mut self, charset: S) -> Self {
+        self.charset = charset.into();
+        self
+    }
+
+    /// Add an inline stylesheet.
+    pub fn stylesheet<S: Into<String>>(mut self, css: S) -> Self {
+        self.stylesheets.push(css.into());
+        self
+    }
+
+    fn write_document<W: Write>(&self, out: &mut W, body: &Model) -> std::fmt::Result {
+        out.write_str("<!DOCTYPE html>\n<html")?;
+        if let Some(lang) = &self.lang {
+            out.write_str(" lang=\"")?;
+            write_escaped(out, lang, true)?;
+            out.write_char('"')?;
+        }
+        out.write_str(">\n<head>\n<meta charset=\"")?;
+        write_escaped(out, &self.charset, true)?;
+        out.write_str("\">\n<title>")?;
+        write_escaped(out, &self.title, false)?;
+        out.write_str("</title>\n")?;
+        for css in &self.stylesheets {
+            out.write_str("<style>\n")?;
+            // Keep the stylesheet from closing its element early.
+            out.write_str(&css.replace("</", "<\\/"))?;
+            out.write_str("\n</style>\n")?;
+        }
+        out.write_str("</head>\n<body>\n")?;
+        body.write_html(out)?;
+        out.write_str("\n</body>\n</html>\n")
+    }
+}
+
+/// Find the value of an entry in an insertion-ordered list.
+fn get_entry<'a, V>(entries: &'a [(String, V)], key: &str) -> Option<&'a V> {
+    entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+/// Set the value of an entry in an insertion-ordered list, keeping its position if present.
+fn set_entry<V>(entries: &mut Vec<(String, V)>, key: String, val: V) {
+    match entries.iter_mut().find(|(k, _)| *k == key) {
+        Some((_, v)) => *v = val,
+        None => entries.push((key, val)),
+    }
+}
+
+fn write_indent<W: Write>(out: &mut W, depth: usize) -> std::fmt::Result {
+    for _ in 0..depth {
+        out.write_str("  ")?;
+    }
+    Ok(())
+}
+
+/// Write text escaped for HTML, as an attribute value or as character data.
+fn write_escaped<W: Write>(out: &mut W, text: &str, in_attr: bool) -> std::fmt::Result {
+    let mut rest = text;
+    while let Some(index) = rest.find(['&', '<', '>', '"']) {
+        let (plain, tail) = rest.split_at(index);
+        out.write_str(plain)?;
+        let c = tail.chars().next().unwrap();
+        match c {
+            '&' => out.write_str("&amp;")?,
+            '"' if in_attr => out.write_str("&quot;")?,
+            '<' if !in_attr => out.write_str("&lt;")?,
+            '>' if !in_attr => out.write_str("&gt;")?,
+            c => out.write_char(c)?,
+        }
+        rest = &tail[c.len_utf8()..];
+    }
+    out.write_str(rest)
+}
+
+/// Markup longer than this is sent to the page in several scripts when filling.
+///
+/// Remarks:
+/// * It bounds the size of a single evaluation, and of the message carrying it to the page.
+/// * Evaluating the pieces costs about the same as one whole script, see the `fill_cost` test.
+pub(crate) const FILL_CHUNK_LEN: usize = 1 << 20;
+
+/// Make the scripts handing markup to the page in pieces, to be assembled by `_lk_take_chunks`.
+pub(crate) fn chunk_scripts(markup: &str, max: usize) -> impl Iterator<Item = String> + '_ {
+    split_chunks(markup, max).map(|chunk| {
+        let mut script = String::with_capacity(chunk.len() + 16);
+        script.push_str("_lk_chunk(");
+        write_js_string(&mut script, chunk);
+        script.push_str(");");
+        script
+    })
+}
+
+/// Split a string into pieces of at most `max` bytes, on character boundaries.
+///
+/// Remarks:
+/// * A character longer than `max` makes a piece of its own.
+fn split_chunks(s: &str, max: usize) -> impl Iterator<Item = &str> {
+    let mut rest = s;
+    std::iter::from_fn(move || {
+        let first = rest.chars().next()?.len_utf8();
+        let mut end = max.clamp(first, rest.len());
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (chunk, tail) = rest.split_at(end);
+        rest = tail;
+        Some(chunk)
+    })
+}
+
+/// Position of an element.
+///
+/// Variants:
+/// * Path: The path from the root node of content.
+/// * IdPath: The path from the element with the given ID.
+/// * HandlePath: The path from the element with the given handle, see `Agent::pin`.
+///
+/// Remarks:
+/// * The path is a sequence of indices of children.
+/// * The path is empty for the root node of content or the element with the given ID or handle.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Position {
+    Path(Vec<usize>),
+    IdPath(String, Vec<usize>),
+    HandlePath(u64, Vec<usize>),
+}
+
+static HANDLE_NEXT: AtomicU64 = AtomicU64::new(0);
+
+fn next_handle() -> u64 {
+    HANDLE_NEXT.fetch_add(1, Ordering::SeqCst)
+}
+
+/// Agent to an element.
+///
+/// Remarks:
+/// * The agent doesn't directly hold the element.
+/// * It doesn't check the correctness of the path or ID.
+/// * It can be written to and parsed from a string, see its `Display` implementation.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Agent {
+    ord: ViewId,
+    position: Position,
+}
+
+impl Agent {
+    pub(crate) fn root_of(ord: ViewId) -> Self {
+        Self {
+            ord,
+            position: Position::Path(vec![]),
+        }
+    }
+
+    fn script_get_element(&self) -> String {
+        let (mut script, path) = match &self.position {
+            Position::Path(path) => ("document.body.children[0]".to_string(), path),
+            Position::IdPath(id, path) => (format!("document.getElementById({})", js_string(id)), path),
+            Position::HandlePath(handle, path) =>
+                (format!("_lk_h({})", js_string(&handle.to_string())), path),
+        };
+        for i in path {
+            write!(script, ".children[{}]", i).unwrap();
+        }
+        script
+    }
+
+    fn script_set_element(&self, target: &str) -> String {
+        let get_script = self.script_get_element();
+        format!("{} = {}", get_script, target)
     }
 
     /// Get the agent to an element with path relative to the current element.
@@ -355,6 +1513,8 @@ impl Agent {
             Position::Path(p) => Position::Path([&p[..], &path[..]].concat()),
             Position::IdPath(id, p) =>
                 Position::IdPath(id.clone(), [&p[..], &path[..]].concat()),
+            Position::HandlePath(handle, p) =>
+                Position::HandlePath(*handle, [&p[..], &path[..]].concat()),
         };
         Agent {
             ord: self.ord,
@@ -362,6 +1522,29 @@ impl Agent {
         }
     }
 
+    /// Get the agent to the element with the given handle in the same WebView.
+    pub(crate) fn at_handle(&self, handle: u64) -> Self {
+        Agent {
+            ord: self.ord,
+            position: Position::HandlePath(handle, vec![]),
+        }
+    }
+
+    /// Get an agent that stays bound to the element it currently points at.
+    ///
+    /// Remarks:
+    /// * The element is stamped with a `data-jo` handle unless it already has one.
+    /// * The new agent keeps addressing the element when others are inserted or removed around it.
+    pub fn pin(&self) -> Self {
+        let handle = next_handle();
+        let script = format!(
+            "_lk_pin({}, {});",
+            self.script_get_element(), js_string(&handle.to_string()),
+        );
+        queue_script(self.ord, script);
+        self.at_handle(handle)
+    }
+
     /// Get the WebView.
     pub fn view(&self) -> Option<View> {
         View::acquire(self.ord)
@@ -370,104 +1553,255 @@ impl Agent {
     /// Bind an callback to a DOM event.
     ///
     /// Remarks:
-    /// * The callback is unique regarding to the event key.
-    ///     * If the callback is already bound, it is replaced.
+    /// * Any number of callbacks can be bound to the same element and event key.
     /// * The callback is called with the agent to the element and the detail of the event.
-    pub fn bind<F>(&self, key: &str, callback: F) -> Callback
+    /// * The returned listener detaches the callback when dropped, see `Listener`.
+    pub fn bind<F>(&self, key: &str, callback: F) -> Listener
         where
             F: FnMut(Agent, HashMap<String, String>) + 'static,
     {
-        let callback = Callback::create(callback);
-        let path: String = self.clone().into();
+        self.bind_with(key, BindOptions::default(), callback)
+    }
+
+    /// Bind an callback to a DOM event with options.
+    ///
+    /// Remarks:
+    /// * See `bind` and `BindOptions`.
+    pub fn bind_with<F>(&self, key: &str, options: BindOptions, callback: F) -> Listener
+        where
+            F: FnMut(Agent, HashMap<String, String>) + 'static,
+    {
+        let (listener, script) = self.bind_script(key, options, callback);
+        queue_script(self.ord, script);
+        listener
+    }
+
+    /// Register the callback and make the script binding it, without sending the script.
+    fn bind_script<F>(&self, key: &str, options: BindOptions, callback: F) -> (Listener, String)
+        where
+            F: FnMut(Agent, HashMap<String, String>) + 'static,
+    {
+        let callback = Callback::create_owned(self.ord, callback);
         let script = format!(
-            "{{let elem = {};_lk_reg_evt(elem, \"{}\", \"{}\", \"{}\");}}",
-            self.script_get_element(), key, path, callback.id,
+            "{{let elem = {};_lk_reg_evt(elem, {}, {}, \"{}\", {});}}",
+            self.script_get_element(), js_string(key), js_string(&self.to_string()), callback.id,
+            options.script_object(),
         );
-        PROXY.with(move |proxy| proxy.borrow().as_ref().unwrap()
-            .send_event(JoEvent::EvalScript { ord: self.ord, script }).unwrap());
-        callback
+        let listener = Listener {
+            ord: self.ord,
+            callback: Some(callback),
+        };
+        (listener, script)
+    }
+
+    /// Bind an callback to a DOM event, deserializing the detail into `T`.
+    ///
+    /// Remarks:
+    /// * By default the event fields are deserialized, along with `value` if the target has one.
+    /// * Use `BindOptions::extract` with `bind_typed_with` to choose what is sent.
+    /// * A detail failing to deserialize raises `Error::InvalidDetail` instead of calling back.
+    #[cfg(feature = "serde")]
+    pub fn bind_typed<T, F>(&self, key: &str, callback: F) -> Listener
+        where
+            T: DeserializeOwned,
+            F: FnMut(Agent, T) + 'static,
+    {
+        self.bind_typed_with(key, BindOptions::default(), callback)
+    }
+
+    /// Bind an callback to a DOM event with options, deserializing the detail into `T`.
+    ///
+    /// Remarks:
+    /// * See `bind_typed` and `BindOptions`.
+    #[cfg(feature = "serde")]
+    pub fn bind_typed_with<T, F>(&self, key: &str, options: BindOptions, mut callback: F) -> Listener
+        where
+            T: DeserializeOwned,
+            F: FnMut(Agent, T) + 'static,
+    {
+        let event = key.to_string();
+        self.bind_with(key, options.json(), move |agent, detail| {
+            let json = detail.get("likit_json").map_or("null", String::as_str);
+            match serde_json::from_str::<T>(json) {
+                Ok(detail) => callback(agent, detail),
+                Err(err) => raise_error(Error::InvalidDetail {
+                    event: event.clone(),
+                    reason: err.to_string(),
+                }),
+            }
+        })
     }
 
-    /// Unbind the callback to a DOM event.
+    /// Unbind all callbacks to a DOM event.
+    ///
+    /// Remarks:
+    /// * Listeners of the same event on other elements are not affected.
+    /// * The callbacks stay registered until their listeners are dropped or the View is destroyed.
     pub fn unbind(&self, key: &str) {
         let script = format!(
-            "let elem = {};_lk_unreg_evt(elem, \"{}\");",
-            self.script_get_element(), key,
+            "{{let elem = {};_lk_rem_evt(elem, {});}}",
+            self.script_get_element(), js_string(key),
         );
-        PROXY.with(move |proxy| proxy.borrow().as_ref().unwrap()
-            .send_event(JoEvent::EvalScript { ord: self.ord, script }).unwrap());
+        queue_script(self.ord, script);
     }
 
     /// Set the specified attribute.
     pub fn set(&self, key: &str, val: &str) {
         let script = format!(
-            "{{let elem = {};elem.setAttribute(\"{}\", \"{}\");}}",
-            self.script_get_element(), key, val,
+            "{{let elem = {};elem.setAttribute({}, {});}}",
+            self.script_get_element(), js_string(key), js_string(val),
         );
-        PROXY.with(move |proxy| proxy.borrow().as_ref().unwrap()
-            .send_event(JoEvent::EvalScript { ord: self.ord, script }).unwrap());
+        queue_script(self.ord, script);
     }
 
     /// Set the specified style.
     pub fn set_style(&self, key: &str, val: &str) {
         let script = format!(
-            "{{let elem = {};elem.style.setProperty(\"{}\", \"{}\");}}",
-            self.script_get_element(), key, val,
+            "{{let elem = {};elem.style.setProperty({}, {});}}",
+            self.script_get_element(), js_string(key), js_string(val),
         );
-        println!("{}", script);
-        PROXY.with(move |proxy| proxy.borrow().as_ref().unwrap()
-            .send_event(JoEvent::EvalScript { ord: self.ord, script }).unwrap());
+        queue_script(self.ord, script);
     }
 }
 
-impl Into<String> for Agent {
-    fn into(self) -> String {
-        match self.position {
-            Position::Path(path) => {
-                let mut notation = format!("{}:", self.ord);
-                for i in path {
-                    notation.push_str(&format!("{},", i));
-                }
-                notation
-            }
-            Position::IdPath(id, path) => {
-                let mut notation = format!("{},{}:", self.ord, id);
-                for i in path {
-                    notation.push_str(&format!("{},", i));
-                }
-                notation
+/// Error of parsing an Agent from its notation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseAgentError {
+    reason: &'static str,
+}
+
+impl Display for ParseAgentError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid agent notation: {}", self.reason)
+    }
+}
+
+impl std::error::Error for ParseAgentError {}
+
+fn agent_error(reason: &'static str) -> ParseAgentError {
+    ParseAgentError { reason }
+}
+
+/// Percent-encode everything but ASCII alphanumerics and `-`, `_`, `.`, `~`.
+fn escape_notation(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' =>
+                escaped.push(byte as char),
+            _ => escaped.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    escaped
+}
+
+fn unescape_notation(s: &str) -> Result<String, ParseAgentError> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(byte) = iter.next() {
+        if byte == b'%' {
+            let hex = [
+                iter.next().ok_or(agent_error("truncated escape"))?,
+                iter.next().ok_or(agent_error("truncated escape"))?,
+            ];
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return Err(agent_error("invalid escape"));
             }
+            let hex = std::str::from_utf8(&hex).unwrap();
+            bytes.push(u8::from_str_radix(hex, 16).unwrap());
+        } else {
+            bytes.push(byte);
         }
     }
+    String::from_utf8(bytes).map_err(|_| agent_error("escaped ID is not UTF-8"))
 }
 
-impl From<&str> for Agent {
-    fn from(s: &str) -> Self {
-        let mut s = s.trim().split(':');
-        let head = s.next().unwrap();
-        let tail = s.next().unwrap();
-        let mut head = head.split(',');
-        let ord = head.next().unwrap().parse::<usize>().unwrap();
-        if let Some(id) = head.next() {
-            let mut path = Vec::new();
-            for i in tail.split(',') {
-                if i.is_empty() { continue; }
-                path.push(i.parse::<usize>().unwrap());
+/// Notation of an Agent.
+///
+/// Remarks:
+/// * It is written as `view:kind:path`, or `view:kind:anchor:path` for anchored positions.
+///     * `view` is the ID of the WebView, e.g. `0.1`.
+///     * `kind` is `p` for `Path`, `i` for `IdPath` and `h` for `HandlePath`.
+///     * `anchor` is the percent-encoded ID or the handle.
+///     * `path` is the comma-separated indices, empty for no path.
+/// * The notation contains no whitespace, quotes, `>` or `,` other than the path separators.
+impl Display for Agent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let path = match &self.position {
+            Position::Path(path) => {
+                write!(f, "{}:p:", self.ord)?;
+                path
             }
-            Agent {
-                ord,
-                position: Position::IdPath(id.to_string(), path),
+            Position::IdPath(id, path) => {
+                write!(f, "{}:i:{}:", self.ord, escape_notation(id))?;
+                path
             }
-        } else {
-            let mut path = Vec::new();
-            for i in tail.split(',') {
-                path.push(i.parse::<usize>().unwrap());
+            Position::HandlePath(handle, path) => {
+                write!(f, "{}:h:{}:", self.ord, handle)?;
+                path
             }
-            Agent {
-                ord,
-                position: Position::Path(path),
+        };
+        for (n, i) in path.iter().enumerate() {
+            if n > 0 {
+                write!(f, ",")?;
             }
+            write!(f, "{}", i)?;
         }
+        Ok(())
+    }
+}
+
+impl FromStr for Agent {
+    type Err = ParseAgentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.trim().split(':').collect();
+        let ord = parts[0].parse::<ViewId>().map_err(|_| agent_error("invalid view ID"))?;
+        let parse_path = |path: &str| -> Result<Vec<usize>, ParseAgentError> {
+            path.split(',')
+                .filter(|i| !i.is_empty())
+                .map(|i| i.parse::<usize>().map_err(|_| agent_error("invalid path index")))
+                .collect()
+        };
+        let position = match parts[1..] {
+            ["p", path] => Position::Path(parse_path(path)?),
+            ["i", id, path] => Position::IdPath(unescape_notation(id)?, parse_path(path)?),
+            ["h", handle, path] => Position::HandlePath(
+                handle.parse().map_err(|_| agent_error("invalid handle"))?,
+                parse_path(path)?,
+            ),
+            _ => return Err(agent_error("unknown position kind or wrong number of parts")),
+        };
+        Ok(Agent { ord, position })
+    }
+}
+
+impl TryFrom<&str> for Agent {
+    type Error = ParseAgentError;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Agent> for String {
+    fn from(agent: Agent) -> Self {
+        agent.to_string()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for Agent {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Agent {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let notation = String::deserialize(deserializer)?;
+        notation.parse().map_err(serde::de::Error::custom)
     }
 }
 
@@ -478,7 +1812,22 @@ pub struct Callback {
 
 type CallbackFunc = Box<dyn FnMut(Agent, HashMap<String, String>)>;
 
-static mut CALLBACKS: BTreeMap<usize, CallbackFunc> = BTreeMap::new();
+/// Registry of callbacks living on user thread.
+///
+/// Remarks:
+/// * A function is taken out of the registry while it is being invoked, leaving `None`.
+///     * This allows a callback to remove itself, e.g. by destroying its own View.
+/// * Callbacks bound through a View or an Agent are owned by the View.
+#[derive(Default)]
+struct CallbackRegistry {
+    funcs: BTreeMap<usize, Option<CallbackFunc>>,
+    owners: BTreeMap<usize, ViewId>,
+    owned: BTreeMap<ViewId, BTreeSet<usize>>,
+}
+
+thread_local! {
+    static CALLBACKS: RefCell<CallbackRegistry> = RefCell::new(CallbackRegistry::default());
+}
 static CALLBACK_ID_NEXT: AtomicUsize = AtomicUsize::new(0);
 
 impl Callback {
@@ -488,15 +1837,33 @@ impl Callback {
             F: FnMut(Agent, HashMap<String, String>) + 'static,
     {
         let id = CALLBACK_ID_NEXT.fetch_add(1, Ordering::SeqCst);
-        unsafe {
-            CALLBACKS.insert(id, Box::new(f));
-        }
+        CALLBACKS.with(|reg| reg.borrow_mut().funcs.insert(id, Some(Box::new(f))));
         Callback { id }
     }
 
+    /// Register a callback owned by a View, so that it is removed along with the View.
+    ///
+    /// Remarks:
+    /// * If the View is already destroyed, the callback is not registered at all.
+    pub(crate) fn create_owned<F>(owner: ViewId, f: F) -> Self
+        where
+            F: FnMut(Agent, HashMap<String, String>) + 'static,
+    {
+        if !is_view_alive(owner) {
+            return Callback { id: CALLBACK_ID_NEXT.fetch_add(1, Ordering::SeqCst) };
+        }
+        let callback = Self::create(f);
+        CALLBACKS.with(|reg| {
+            let mut reg = reg.borrow_mut();
+            reg.owners.insert(callback.id, owner);
+            reg.owned.entry(owner).or_default().insert(callback.id);
+        });
+        callback
+    }
+
     /// Get a callback by its id.
     pub fn get(id: usize) -> Option<Self> {
-        if unsafe { CALLBACKS.contains_key(&id) } {
+        if CALLBACKS.with(|reg| reg.borrow().funcs.contains_key(&id)) {
             Some(Callback { id })
         } else {
             None
@@ -505,15 +1872,293 @@ impl Callback {
 
     /// Remove the callback from the registry.
     pub fn remove(self) {
-        unsafe {
-            CALLBACKS.remove(&self.id);
-        }
+        CALLBACKS.with(|reg| {
+            let mut reg = reg.borrow_mut();
+            reg.funcs.remove(&self.id);
+            if let Some(owner) = reg.owners.remove(&self.id) {
+                if let Some(owned) = reg.owned.get_mut(&owner) {
+                    owned.remove(&self.id);
+                }
+            }
+        });
     }
 
     /// Invoke the callback.
+    ///
+    /// Remarks:
+    /// * Invoking a callback from within itself does nothing.
     pub fn invoke(&self, agent: Agent, detail: HashMap<String, String>) {
-        let callback = unsafe { CALLBACKS.get_mut(&self.id).unwrap() };
-        callback(agent, detail)
+        let callback = CALLBACKS.with(|reg| reg.borrow_mut().funcs
+            .get_mut(&self.id)
+            .and_then(Option::take));
+        let mut callback = if let Some(cb) = callback { cb } else { return };
+        callback(agent, detail);
+        CALLBACKS.with(|reg| {
+            if let Some(slot) = reg.borrow_mut().funcs.get_mut(&self.id) {
+                *slot = Some(callback);
+            }
+        });
+    }
+}
+
+/// Remove all callbacks owned by a View.
+pub(crate) fn remove_owned_callbacks(owner: ViewId) {
+    CALLBACKS.with(|reg| {
+        let mut reg = reg.borrow_mut();
+        for id in reg.owned.remove(&owner).unwrap_or_default() {
+            reg.funcs.remove(&id);
+            reg.owners.remove(&id);
+        }
+    });
+}
+
+/// Snapshot of the resources held on user thread.
+///
+/// Fields:
+/// * `views`: IDs of the live WebViews.
+/// * `callbacks`: Number of registered callbacks.
+/// * `callbacks_per_view`: Number of callbacks owned by each live WebView.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DebugReport {
+    pub views: Vec<ViewId>,
+    pub callbacks: usize,
+    pub callbacks_per_view: BTreeMap<ViewId, usize>,
+}
+
+/// Report the live WebViews and callbacks, useful for hunting leaks.
+///
+/// Remarks:
+/// * Only operate with the user runtime thread.
+pub fn joestar_debug_report() -> DebugReport {
+    let views = live_views();
+    CALLBACKS.with(|reg| {
+        let reg = reg.borrow();
+        DebugReport {
+            views,
+            callbacks: reg.funcs.len(),
+            callbacks_per_view: reg.owned.iter()
+                .map(|(owner, owned)| (*owner, owned.len()))
+                .collect(),
+        }
+    })
+}
+
+/// Options of a DOM event binding.
+///
+/// Remarks:
+/// * The decisions are made synchronously in the page, as the callback only runs later on user thread.
+/// * `prevent_default` has no effect on a `passive` listener.
+/// * A `once` listener is detached after the first event sent to user thread.
+///     * Events filtered out by `delegate` or dropped by the rate don't count.
+///     * Its callback stays until the guard is dropped.
+/// * The rate only limits what is sent to user thread, the flags still apply to every event.
+/// * With `delegate`, the flags and the rate only apply to events matching the selector.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct BindOptions {
+    prevent_default: bool,
+    stop_propagation: bool,
+    stop_immediate_propagation: bool,
+    capture: bool,
+    once: bool,
+    passive: bool,
+    rate: Rate,
+    delegate: Option<String>,
+    extract: Option<String>,
+    json: bool,
+}
+
+/// Rate at which events of a binding are sent to user thread.
+///
+/// Variants:
+/// * Every: Send every event.
+/// * Throttle: Send at most one event per period, the last one of a burst is sent at its end.
+/// * Debounce: Send the last event once no event happened for the period.
+/// * Latest: Send at most one event per animation frame, keeping only the latest one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Rate {
+    #[default]
+    Every,
+    Throttle(Duration),
+    Debounce(Duration),
+    Latest,
+}
+
+impl BindOptions {
+    /// Create options with every flag off.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Call `preventDefault` on the event, e.g. to stop a form submit from navigating.
+    pub fn prevent_default(mut self) -> Self {
+        self.prevent_default = true;
+        self
+    }
+
+    /// Call `stopPropagation` on the event, keeping it from bubbling to parent handlers.
+    pub fn stop_propagation(mut self) -> Self {
+        self.stop_propagation = true;
+        self
+    }
+
+    /// Call `stopImmediatePropagation` on the event, also skipping other handlers of the element.
+    pub fn stop_immediate_propagation(mut self) -> Self {
+        self.stop_immediate_propagation = true;
+        self
+    }
+
+    /// Listen in the capture phase.
+    pub fn capture(mut self) -> Self {
+        self.capture = true;
+        self
+    }
+
+    /// Detach the listener after the first event sent to user thread.
+    pub fn once(mut self) -> Self {
+        self.once = true;
+        self
+    }
+
+    /// Mark the listener as passive, promising not to prevent the default action.
+    pub fn passive(mut self) -> Self {
+        self.passive = true;
+        self
+    }
+
+    /// Set the rate at which events are sent to user thread.
+    pub fn rate(mut self, rate: Rate) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    /// Shorthand of `rate(Rate::Throttle(period))`.
+    pub fn throttle(self, period: Duration) -> Self {
+        self.rate(Rate::Throttle(period))
+    }
+
+    /// Shorthand of `rate(Rate::Debounce(period))`.
+    pub fn debounce(self, period: Duration) -> Self {
+        self.rate(Rate::Debounce(period))
+    }
+
+    /// Shorthand of `rate(Rate::Latest)`.
+    pub fn latest(self) -> Self {
+        self.rate(Rate::Latest)
+    }
+
+    /// Delegate the binding to descendants matching a CSS selector.
+    ///
+    /// Remarks:
+    /// * The callback receives the agent to the closest matching element of the event target.
+    ///     * It is located by its path relative to the bound element.
+    /// * Events whose target has no matching ancestor inside the bound element are ignored.
+    pub fn delegate<S: Into<String>>(mut self, selector: S) -> Self {
+        self.delegate = Some(selector.into());
+        self
+    }
+
+    /// Extract the data sent with the event by a JavaScript function.
+    ///
+    /// Remarks:
+    /// * The function takes the event and returns a JSON-serializable value, e.g. `e => e.target.dataset`.
+    /// * The value is sent as the `likit_json` entry of the detail.
+    /// * It is not checked nor escaped, so be careful.
+    pub fn extract<S: Into<String>>(mut self, function: S) -> Self {
+        self.extract = Some(function.into());
+        self.json = true;
+        self
+    }
+
+    /// Send the event fields as the `likit_json` entry of the detail, keeping their types.
+    pub fn json(mut self) -> Self {
+        self.json = true;
+        self
+    }
+
+    fn script_object(&self) -> String {
+        let extract = match &self.extract {
+            Some(function) => format!(", extract: ({function})"),
+            None => String::new(),
+        };
+        let delegate = match &self.delegate {
+            Some(selector) => format!(", delegate: {}", js_string(selector)),
+            None => String::new(),
+        };
+        let rate = match self.rate {
+            Rate::Every => String::new(),
+            Rate::Throttle(period) => format!(", throttle: {}", period.as_millis()),
+            Rate::Debounce(period) => format!(", debounce: {}", period.as_millis()),
+            Rate::Latest => ", latest: true".to_string(),
+        };
+        format!(
+            "{{preventDefault: {}, stopPropagation: {}, stopImmediatePropagation: {}, \
+            capture: {}, once: {}, passive: {}, json: {}{}{}{}}}",
+            self.prevent_default, self.stop_propagation, self.stop_immediate_propagation,
+            self.capture, self.once, self.passive, self.json, rate, delegate, extract,
+        )
+    }
+}
+
+/// Quote a string as a JavaScript string literal.
+pub(crate) fn js_string(s: &str) -> String {
+    let mut literal = String::with_capacity(s.len() + 2);
+    write_js_string(&mut literal, s);
+    literal
+}
+
+/// Append a string quoted as a JavaScript string literal.
+fn write_js_string(literal: &mut String, s: &str) {
+    literal.reserve(s.len() + 2);
+    literal.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\u{2028}' => literal.push_str("\\u2028"),
+            '\u{2029}' => literal.push_str("\\u2029"),
+            c if c.is_control() => write!(literal, "\\u{:04x}", c as u32).unwrap(),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+}
+
+/// Guard of a callback bound to a DOM event.
+///
+/// Remarks:
+/// * Dropping the guard or calling `unbind` detaches exactly this listener and removes its callback.
+/// * Call `forget` to keep the listener until the View is destroyed.
+#[must_use = "the listener is detached as soon as it is dropped, call `forget` to keep it"]
+pub struct Listener {
+    ord: ViewId,
+    callback: Option<Callback>,
+}
+
+impl Listener {
+    /// Detach the listener and remove its callback.
+    pub fn unbind(self) {}
+
+    /// Keep the listener bound until the View is destroyed.
+    pub fn forget(mut self) {
+        self.callback = None;
+    }
+
+    /// Get the ID of the WebView the listener belongs to.
+    pub fn view_id(&self) -> ViewId {
+        self.ord
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let callback = if let Some(cb) = self.callback.take() { cb } else { return };
+        if is_view_alive(self.ord) {
+            let script = format!("_lk_rem_cb(\"{}\");", callback.id);
+            queue_script(self.ord, script);
+        }
+        callback.remove();
     }
 }
 
@@ -540,17 +2185,453 @@ impl View {
         })
     }
 
+    /// Bind a callback fired once the page of the View is ready.
+    ///
+    /// Remarks:
+    /// * Operations issued before readiness are buffered and applied in order before it fires.
+    ///     * Filling and binding right after `View::new` is therefore safe without waiting.
+    /// * If the View is already ready when binding, the callback fires right away.
+    pub fn on_ready<F>(&self, mut callback: F) -> Callback
+        where
+            F: FnMut(View) + 'static,
+    {
+        self.bind(ViewEventKey::Ready, move |view, _| callback(view))
+    }
+
+    /// Bind a handler deciding whether the View may close when requested.
+    ///
+    /// Remarks:
+    /// * The View is destroyed if the handler returns `true`, and kept open otherwise.
     pub fn on_close_request<F>(&self, mut callback: F) -> Callback
         where
-            F: FnMut() + 'static,
+            F: FnMut() -> bool + 'static,
     {
+        let ord = self.ord;
         self.bind(ViewEventKey::CloseRequest, move |_, _| {
-            callback();
+            if callback() {
+                if let Some(view) = View::acquire(ord) {
+                    view.destroy();
+                }
+            }
         })
     }
 }
 
+/// Terminate the runtime with exit code 0.
+///
+/// Remarks:
+/// * See `joestar_terminate_with`.
 pub fn joestar_terminate() {
+    joestar_terminate_with(0)
+}
+
+/// Terminate the runtime with the given exit code.
+///
+/// Remarks:
+/// * Callbacks already queued on user thread still run, followed by the exit hooks.
+/// * All WebViews are destroyed afterwards and the process exits with the code.
+/// * If termination is requested more than once, the first code is used.
+pub fn joestar_terminate_with(code: i32) {
     PROXY.with(move |proxy| proxy.borrow().as_ref().unwrap()
-        .send_event(JoEvent::Terminate).unwrap());
-}
\ No newline at end of file
+        .send_event(JoEvent::Terminate { code }).unwrap());
+}
+
+/// Register a hook to be run on user thread when the runtime exits.
+///
+/// Remarks:
+/// * Only operate with the user runtime thread.
+/// * Hooks run in registration order and receive the exit code.
+/// * WebViews may already be gone while the hooks run.
+///     * With `ExitPolicy::LastViewDestroyed`, the exit is caused by destroying the last one.
+///     * Check `View::is_alive` before operating on a View.
+pub fn joestar_on_exit<F>(hook: F)
+    where
+        F: FnOnce(i32) + 'static,
+{
+    EXIT_HOOKS.with(|hooks| hooks.borrow_mut().push(Box::new(hook)));
+}
+
+/// Pass an error to the error hook of the application.
+///
+/// Remarks:
+/// * Only operate with the user runtime thread.
+pub fn joestar_report_error(error: Error) {
+    raise_error(error);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stamped_models_give_agents_by_handle() {
+        let view = detached_view();
+        let mut model = Model::new("ul")
+            .child(Model::new("li").id("first"))
+            .child(Model::new("li").attr("data-jo", "7"))
+            .child(Model::new("li"));
+        assert_eq!(view.agent_of(model.child_elements().nth(2).unwrap()), None);
+
+        model.stamp_handles();
+        let handles: Vec<_> = model.walk().map(|m| m.handle().unwrap()).collect();
+        assert_eq!(handles[2], 7, "existing handles are kept");
+        let unique: BTreeSet<_> = handles.iter().collect();
+        assert_eq!(unique.len(), 4);
+
+        let item = model.find_by_id("first").unwrap();
+        assert_eq!(view.agent_of(item), Some(view.at_handle(handles[1])));
+        assert_eq!(view.at_handle(7).to_string().parse::<Agent>().unwrap(), view.at_handle(7));
+    }
+
+    #[test]
+    fn queued_scripts_are_coalesced_per_view_in_order() {
+        let first = detached_view();
+        let second = detached_view();
+        first.lookup("a").set("title", "1");
+        second.lookup("a").set("title", "2");
+        first.lookup("b").set("title", "3");
+        assert_eq!(SCRIPT_QUEUE.with(|queue| queue.borrow().len()), 2);
+
+        let script = take_queued_script(first.id());
+        let blocks: Vec<_> = script.split('\n').collect();
+        assert_eq!(blocks, [
+            "try{",
+            r#"{let elem = document.getElementById("a");elem.setAttribute("title", "1");}"#,
+            "}catch(e){console.error(e)}",
+            "try{",
+            r#"{let elem = document.getElementById("b");elem.setAttribute("title", "3");}"#,
+            "}catch(e){console.error(e)}",
+        ]);
+        assert!(take_queued_script(second.id()).contains(r#""title", "2""#));
+    }
+
+    #[test]
+    fn forgotten_views_release_their_resources() {
+        let view = detached_view();
+        let other = detached_view();
+        view.fill(Model::new("button").id("ok").on("click", |_, _| {}));
+        let _listener = view.lookup("ok").bind("focus", |_, _| {});
+        request_reply(view.id(), "1", |_| {});
+        request_reply(other.id(), "2", |_| {});
+        let report = joestar_debug_report();
+        assert_eq!(report.callbacks_per_view.get(&view.id()), Some(&2));
+        assert!(MOUNTED.with(|mounted| mounted.borrow().contains_key(&view.id())));
+        take_queued_script(view.id());
+
+        forget_view(view.id());
+        let report = joestar_debug_report();
+        assert!(!report.views.contains(&view.id()));
+        assert!(report.views.contains(&other.id()));
+        assert_eq!(report.callbacks_per_view.get(&view.id()), None);
+        assert!(MOUNTED.with(|mounted| !mounted.borrow().contains_key(&view.id())));
+        let owners: Vec<_> = REPLIES.with(|replies| replies.borrow().values()
+            .map(|(owner, _)| *owner)
+            .collect());
+        assert_eq!(owners, [other.id()]);
+        assert_eq!(take_queued_script(view.id()), "", "nothing is queued for a forgotten view");
+        take_queued_script(other.id());
+    }
+
+    #[test]
+    fn stale_handles_do_not_reach_reused_slots() {
+        let old = detached_view();
+        let old_id = old.id();
+        forget_view(old_id);
+        let new = detached_view();
+        assert_eq!(new.id().index, old_id.index, "the slot is reused");
+        assert_ne!(new.id(), old_id);
+        assert!(View::acquire(old_id).is_none());
+        assert_eq!(View::acquire(new.id()), Some(new.clone()));
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        set_error_hook(Some(Box::new(move |error| tx.send(error.clone()).unwrap())));
+        old.lookup("a").set("title", "old");
+        set_error_hook(None);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [Error::StaleView(old_id)]);
+        assert_eq!(take_queued_script(new.id()), "", "nothing reaches the new view");
+        forget_view(new.id());
+    }
+
+    #[test]
+    fn chunks_split_on_char_boundaries() {
+        let text = "aé🦀\u{2028}<p>\"x\"</p>ü".repeat(7);
+        for max in 1..=16 {
+            let chunks: Vec<_> = split_chunks(&text, max).collect();
+            assert_eq!(chunks.concat(), text);
+            for chunk in chunks {
+                assert!(chunk.len() <= max || chunk.chars().count() == 1, "{max} {chunk:?}");
+                assert!(!chunk.is_empty());
+            }
+        }
+        assert_eq!(split_chunks("", 4).count(), 0);
+    }
+
+    #[test]
+    fn html_keeps_id_first_and_insertion_order() {
+        let model = Model::new("div")
+            .attr("data-b", "2")
+            .style("color", "red")
+            .attr("data-a", "1")
+            .id("main")
+            .style("margin", "0")
+            .attr("data-b", "3")
+            .style("color", "blue")
+            .attr("hidden", true)
+            .attr("data-a", false);
+        assert_eq!(
+            model.to_html(),
+            r#"<div id="main" data-b="3" hidden style="color: blue; margin: 0; "></div>"#,
+        );
+    }
+
+    #[test]
+    fn html_escapes_text_and_attributes() {
+        let model = Model::new("p")
+            .id("a\"b")
+            .attr("title", r#"x "y" & <z>"#)
+            .style("font-family", r#""Fira Sans" & co"#)
+            .text(r#"1 < 2 & "3" > 0 é"#)
+            .comment(" note ")
+            .child(Model::new("script").text("if (a < b && c) {}"))
+            .child(Model::new("style").text("a > b { color: red }"));
+        assert_eq!(
+            model.to_html(),
+            concat!(
+                r#"<p id="a&quot;b" title="x &quot;y&quot; &amp; <z>" "#,
+                r#"style="font-family: &quot;Fira Sans&quot; &amp; co; ">"#,
+                r#"1 &lt; 2 &amp; "3" &gt; 0 é<!-- note -->"#,
+                r#"<script>if (a < b && c) {}</script><style>a > b { color: red }</style></p>"#,
+            ),
+        );
+    }
+
+    #[test]
+    fn html_writes_void_elements_without_end_tags() {
+        let model = Model::new("form")
+            .child(Model::new("input").attr("disabled", true).attr("value", "x"))
+            .child(Model::new("br"))
+            .child(Model::new("img").attr("alt", ""))
+            .child(Model::new("textarea"));
+        assert_eq!(
+            model.to_html(),
+            r#"<form><input disabled value="x"><br><img alt=""><textarea></textarea></form>"#,
+        );
+    }
+
+    #[test]
+    fn attributes_and_styles_change_in_place() {
+        let mut model = Model::new("input").attr("type", "text").attr("value", "a").style("color", "red");
+        model.set_attr("value", "b");
+        model.set_attr("disabled", true);
+        model.set_attr("name", Some("q"));
+        assert_eq!(model.attrs().collect::<Vec<_>>(), [
+            ("type", Some("text")), ("value", Some("b")), ("disabled", None), ("name", Some("q")),
+        ]);
+        model.set_attr("type", false);
+        model.set_attr("name", None::<&str>);
+        assert_eq!(model.get_attr("type"), None);
+        assert_eq!(model.remove_attr("disabled"), Some(AttrValue::Present));
+        assert_eq!(model.remove_attr("value"), Some(AttrValue::Value("b".to_string())));
+        assert_eq!(model.remove_attr("value"), None);
+        assert_eq!(model.attrs().count(), 0);
+
+        model.set_style("width", "1px");
+        model.set_style("color", "blue");
+        assert_eq!(model.styles().collect::<Vec<_>>(), [("color", "blue"), ("width", "1px")]);
+        assert_eq!(model.remove_style("color"), Some("blue".to_string()));
+        assert_eq!(model.remove_style("color"), None);
+        assert_eq!(model.to_html(), r#"<input style="width: 1px; ">"#);
+    }
+
+    #[test]
+    fn find_all_mut_stops_at_outermost_matches() {
+        let mut model = Model::new("div").attr("class", "box")
+            .child(Model::new("div").attr("class", "box").id("inner")
+                .child(Model::new("p").text("a")))
+            .child(Model::new("section")
+                .child(Model::new("div").attr("class", "box").id("other")));
+        let is_box = |m: &Model| m.get_attr("class") == Some("box");
+
+        let ids: Vec<_> = model.find_all(is_box).iter().map(|m| m.get_id()).collect();
+        assert_eq!(ids, [None, Some("inner"), Some("other")], "find_all returns nested matches");
+        let found = model.find_all_mut(is_box);
+        assert_eq!(found.len(), 1, "find_all_mut doesn't search inside a match");
+        assert_eq!(found[0].get_id(), None);
+
+        for found in model.child_elements_mut().flat_map(|child| child.find_all_mut(is_box)) {
+            found.set_attr("title", "found");
+        }
+        let titled: Vec<_> = model.find_all(|m| m.get_attr("title").is_some()).iter()
+            .map(|m| m.get_id())
+            .collect();
+        assert_eq!(titled, [Some("inner"), Some("other")]);
+    }
+
+    #[test]
+    fn walk_mut_visits_in_document_order() {
+        let mut model = Model::new("ol")
+            .child(Model::new("li").child(Model::new("b")))
+            .child(Model::new("li"));
+        let mut order = vec![];
+        model.walk_mut(|m| {
+            order.push(m.tag().to_string());
+            if m.tag() == "ol" {
+                m.child_nodes_mut().push(Node::Element(Model::new("hr")));
+            }
+            m.set_attr("data-seen", "1");
+        });
+        assert_eq!(order, ["ol", "li", "b", "li", "hr"], "children added on the way are visited");
+        assert!(model.walk().all(|m| m.get_attr("data-seen") == Some("1")));
+    }
+
+    #[test]
+    fn pretty_html_indents_by_depth() {
+        let model = Model::new("ul").id("list")
+            .child(Model::new("li").text("one"))
+            .child(Model::new("li").child(Model::new("b").text("two")).text("tail"))
+            .child(Model::new("li").child(Model::new("input")).text("  \n "))
+            .child(Model::new("li"))
+            .comment("end");
+        assert_eq!(model.to_html_pretty(), concat!(
+            "<ul id=\"list\">\n",
+            "  <li>one</li>\n",
+            "  <li>\n",
+            "    <b>two</b>\n",
+            "    tail\n",
+            "  </li>\n",
+            "  <li>\n",
+            "    <input>\n",
+            "  </li>\n",
+            "  <li></li>\n",
+            "  <!--end-->\n",
+            "</ul>\n",
+        ));
+        assert_eq!(Model::new("br").to_html_pretty(), "<br>\n");
+    }
+
+    #[test]
+    fn document_has_default_head() {
+        let document = Model::new("p").text("Hi").render_document(&DocumentOptions::new());
+        assert_eq!(document, "<!DOCTYPE html>\n\
+            <html>\n\
+            <head>\n\
+            <meta charset=\"utf-8\">\n\
+            <title></title>\n\
+            </head>\n\
+            <body>\n\
+            <p>Hi</p>\n\
+            </body>\n\
+            </html>\n");
+    }
+
+    #[test]
+    fn document_escapes_head_and_keeps_styles_open() {
+        let options = DocumentOptions::new()
+            .title("<Tom & \"Jerry\">")
+            .lang("en\"><script>")
+            .stylesheet("p::after { content: \"</style><script>\" }")
+            .stylesheet("br { display: none }");
+        let body = Model::new("form")
+            .child(Model::new("input").attr("value", "a<b"))
+            .child(Model::new("br"))
+            .child(Model::new("hr").id("end"));
+        assert_eq!(body.render_document(&options), "<!DOCTYPE html>\n\
+            <html lang=\"en&quot;><script>\">\n\
+            <head>\n\
+            <meta charset=\"utf-8\">\n\
+            <title>&lt;Tom &amp; \"Jerry\"&gt;</title>\n\
+            <style>\n\
+            p::after { content: \"<\\/style><script>\" }\n\
+            </style>\n\
+            <style>\n\
+            br { display: none }\n\
+            </style>\n\
+            </head>\n\
+            <body>\n\
+            <form><input value=\"a<b\"><br><hr id=\"end\"></form>\n\
+            </body>\n\
+            </html>\n");
+    }
+
+    #[test]
+    fn comments_cannot_end_early() {
+        let model = Model::new("div").comment("a-->b").comment("->x").comment("y-");
+        assert_eq!(model.to_html(), "<div><!--a- ->b--><!-- ->x--><!--y- --></div>");
+    }
+
+    /// Deterministic pseudo-random numbers, enough to vary the generated cases.
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self, bound: usize) -> usize {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (self.0 >> 33) as usize % bound
+        }
+
+        fn string(&mut self) -> String {
+            const CHARS: [char; 16] = [
+                'a', 'Z', '0', '-', '_', '.', '~', ':', ',', '%', '"', '\\', ' ', '\n', 'é', '🦀',
+            ];
+            (0..self.next(8)).map(|_| CHARS[self.next(CHARS.len())]).collect()
+        }
+
+        fn path(&mut self) -> Vec<usize> {
+            (0..self.next(4)).map(|_| self.next(1000)).collect()
+        }
+    }
+
+    #[test]
+    fn agent_notation_round_trips() {
+        let mut rng = Lcg(42);
+        for _ in 0..2000 {
+            let ord = ViewId { index: rng.next(100), generation: rng.next(100) as u32 };
+            let position = match rng.next(3) {
+                0 => Position::Path(rng.path()),
+                1 => Position::IdPath(rng.string(), rng.path()),
+                _ => Position::HandlePath(rng.next(usize::MAX) as u64, rng.path()),
+            };
+            let agent = Agent { ord, position };
+            let notation = agent.to_string();
+            let parts = if matches!(agent.position, Position::Path(_)) { 3 } else { 4 };
+            assert_eq!(notation.split(':').count(), parts, "{notation}");
+            assert_eq!(notation.parse::<Agent>(), Ok(agent.clone()), "{notation}");
+            assert_eq!(Agent::try_from(String::from(agent.clone()).as_str()), Ok(agent));
+        }
+    }
+
+    #[test]
+    fn agent_notation_escapes_separators() {
+        let agent = Agent {
+            ord: ViewId { index: 1, generation: 2 },
+            position: Position::IdPath("a:b,c%d".into(), vec![3, 4]),
+        };
+        assert_eq!(agent.to_string(), "1.2:i:a%3Ab%2Cc%25d:3,4");
+        assert_eq!(Agent::root_of(agent.ord).to_string(), "1.2:p:");
+    }
+
+    #[test]
+    fn malformed_agent_notation_is_rejected() {
+        let cases = [
+            ("", "invalid view ID"),
+            ("1:p:", "invalid view ID"),
+            ("x.0:p:", "invalid view ID"),
+            ("0.0", "unknown position kind or wrong number of parts"),
+            ("0.0:q:1", "unknown position kind or wrong number of parts"),
+            ("0.0:p:1:2", "unknown position kind or wrong number of parts"),
+            ("0.0:i:a", "unknown position kind or wrong number of parts"),
+            ("0.0:p:1,x", "invalid path index"),
+            ("0.0:p:-1", "invalid path index"),
+            ("0.0:h:x:", "invalid handle"),
+            ("0.0:h:-1:", "invalid handle"),
+            ("0.0:i:%:", "truncated escape"),
+            ("0.0:i:%4:", "truncated escape"),
+            ("0.0:i:%zz:", "invalid escape"),
+            ("0.0:i:%+1:", "invalid escape"),
+            ("0.0:i:%FF:", "escaped ID is not UTF-8"),
+        ];
+        for (notation, reason) in cases {
+            assert_eq!(notation.parse::<Agent>(), Err(agent_error(reason)), "{notation:?}");
+        }
+
+    }
+}
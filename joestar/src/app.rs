@@ -0,0 +1,187 @@
+use std::fmt::{Debug, Formatter};
+use wry::application::event_loop::EventLoop;
+
+use crate::api::*;
+use crate::rt::*;
+
+/// Entry point of a Joestar application.
+///
+/// Remarks:
+/// * Use `Joestar::builder` to configure and launch the runtime.
+pub struct Joestar;
+
+impl Joestar {
+    /// Create a builder with default options.
+    pub fn builder() -> JoestarBuilder {
+        JoestarBuilder::default()
+    }
+}
+
+/// Policy deciding when the runtime exits on its own.
+///
+/// Variants:
+/// * Explicit: Only exit when `joestar_terminate` is called.
+/// * LastViewDestroyed: Also exit once the last View has been destroyed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ExitPolicy {
+    #[default]
+    Explicit,
+    LastViewDestroyed,
+}
+
+/// Behavior of a View when its window is requested to close and no handler is bound.
+///
+/// Variants:
+/// * Destroy: Destroy the View.
+/// * Ignore: Keep the View open.
+///
+/// Remarks:
+/// * A View with a close request handler leaves the decision to the handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum CloseBehavior {
+    #[default]
+    Destroy,
+    Ignore,
+}
+
+pub type UserInit = Box<dyn FnOnce() + Send>;
+pub type ErrorHook = Box<dyn Fn(&Error) + Send>;
+
+/// Builder of a Joestar application.
+///
+/// Remarks:
+/// * The initializer and the error hook are moved to and invoked on the user thread.
+/// * The error hook defaults to logging the error.
+#[derive(Default)]
+pub struct JoestarBuilder {
+    init: Option<UserInit>,
+    default_spec: Spec,
+    error_hook: Option<ErrorHook>,
+    log_console: bool,
+    exit_policy: ExitPolicy,
+    close_behavior: CloseBehavior,
+    stamp_handles: bool,
+}
+
+impl Debug for JoestarBuilder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JoestarBuilder")
+            .field("default_spec", &self.default_spec)
+            .field("log_console", &self.log_console)
+            .field("exit_policy", &self.exit_policy)
+            .field("close_behavior", &self.close_behavior)
+            .field("stamp_handles", &self.stamp_handles)
+            .finish_non_exhaustive()
+    }
+}
+
+impl JoestarBuilder {
+    /// Set the initializer to be invoked on user thread.
+    ///
+    /// Remarks:
+    /// * It may capture anything that can be sent to the user thread, like parsed arguments.
+    pub fn init<F>(mut self, init: F) -> Self
+        where
+            F: FnOnce() + Send + 'static,
+    {
+        self.init = Some(Box::new(init));
+        self
+    }
+
+    /// Set the Spec used by `View::new_default`.
+    pub fn default_spec(mut self, spec: Spec) -> Self {
+        self.default_spec = spec;
+        self
+    }
+
+    /// Set the hook receiving errors raised on user thread, including panics in callbacks.
+    pub fn error_hook<F>(mut self, hook: F) -> Self
+        where
+            F: Fn(&Error) + Send + 'static,
+    {
+        self.error_hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Forward the JavaScript console output of every View to the logger.
+    ///
+    /// Remarks:
+    /// * Messages are logged with the target `joestar::console`.
+    pub fn log_console(mut self, enabled: bool) -> Self {
+        self.log_console = enabled;
+        self
+    }
+
+    /// Set the exit policy of the runtime.
+    pub fn exit_policy(mut self, policy: ExitPolicy) -> Self {
+        self.exit_policy = policy;
+        self
+    }
+
+    /// Set the behavior of Views without a close request handler.
+    pub fn close_behavior(mut self, behavior: CloseBehavior) -> Self {
+        self.close_behavior = behavior;
+        self
+    }
+
+    /// Stamp every element filled into a View with a `data-jo` handle.
+    ///
+    /// Remarks:
+    /// * Agents derived from stamped elements keep addressing them across structural changes.
+    /// * The handles are looked up in constant time by the page.
+    pub fn stamp_handles(mut self, enabled: bool) -> Self {
+        self.stamp_handles = enabled;
+        self
+    }
+
+    /// Takes over the main thread and launch Joestar runtime.
+    ///
+    /// Remarks:
+    /// * It takes over the main thread and (likely) never returns.
+    /// * If a logger is to be set up, it should be done before calling this function.
+    pub fn run(self) {
+        let event_loop = EventLoop::<JoEvent>::with_user_event();
+        let mut rt_state = RtState::new(event_loop.create_proxy(), RtConfig {
+            log_console: self.log_console,
+            exit_policy: self.exit_policy,
+            close_behavior: self.close_behavior,
+        });
+        rt_state.proxy.send_event(JoEvent::UserLaunch {
+            launch: UserLaunch {
+                init: self.init,
+                default_spec: self.default_spec,
+                error_hook: self.error_hook,
+                stamp_handles: self.stamp_handles,
+            },
+        }).unwrap();
+        event_loop.run(move |
+            event,
+            window_target,
+            control_flow
+        | {
+            handle_wry_event(
+                &mut rt_state,
+                event,
+                window_target,
+                control_flow
+            );
+        });
+    }
+}
+
+/// Everything the user thread needs to get started.
+pub(crate) struct UserLaunch {
+    pub(crate) init: Option<UserInit>,
+    pub(crate) default_spec: Spec,
+    pub(crate) error_hook: Option<ErrorHook>,
+    pub(crate) stamp_handles: bool,
+}
+
+impl Debug for UserLaunch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UserLaunch")
+            .field("default_spec", &self.default_spec)
+            .field("stamp_handles", &self.stamp_handles)
+            .finish_non_exhaustive()
+    }
+}
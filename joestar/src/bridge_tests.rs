@@ -0,0 +1,398 @@
+//! Tests running the bridge of `index.html` in Node.js against the minimal DOM of `test_dom.js`.
+//!
+//! Scripts queued by the API are evaluated there, and the messages posted back are fed into the
+//! same parsing and dispatching as the runtime does. Without `node` on the path they are skipped.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{ErrorKind, Write};
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::api::*;
+use crate::rt::parse_event_message;
+
+/// Run a page script after the bridge and get the messages it posted, except `@ready`.
+fn run_page(page: &str) -> Option<Vec<String>> {
+    let index = include_str!("index.html");
+    let start = index.find("<script id=\"__lk_bridge\">").unwrap();
+    let start = start + index[start..].find('>').unwrap() + 1;
+    let end = start + index[start..].find("</script>").unwrap();
+    let program = [include_str!("test_dom.js"), &index[start..end], page].join("\n");
+    let mut node = match Command::new("node")
+        .arg("--expose-gc")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn() {
+        Ok(node) => node,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            eprintln!("node not found, skipping bridge test");
+            return None;
+        }
+        Err(err) => panic!("failed to run node: {err}"),
+    };
+    node.stdin.take().unwrap().write_all(program.as_bytes()).unwrap();
+    let output = node.wait_with_output().unwrap();
+    assert!(output.status.success(), "page failed:\n{}", String::from_utf8_lossy(&output.stderr));
+    let posted = String::from_utf8(output.stdout).unwrap();
+    Some(posted.split('\0').filter(|m| *m != "@ready").map(String::from).collect())
+}
+
+/// Invoke the callbacks of the posted event messages, like the runtime does.
+fn deliver(messages: &[String]) {
+    for message in messages {
+        let (agent, cb_index, detail) = parse_event_message(message)
+            .unwrap_or_else(|| panic!("malformed event message: {message:?}"));
+        if let Some(callback) = Callback::get(cb_index) {
+            callback.invoke(agent, detail);
+        }
+    }
+}
+
+type Hits = Rc<RefCell<Vec<Agent>>>;
+
+/// Record the agents a callback is invoked with.
+fn recorder() -> (Hits, impl FnMut(Agent, HashMap<String, String>)) {
+    let hits = Rc::new(RefCell::new(Vec::new()));
+    let sink = hits.clone();
+    (hits, move |agent, _| sink.borrow_mut().push(agent))
+}
+
+const TWO_BUTTONS: &str = r#"
+document.body.append(el("div", {}, el("button", {id: "first"}), el("button", {id: "second"})))
+let first = document.getElementById("first")
+let second = document.getElementById("second")
+"#;
+
+#[test]
+fn same_tag_siblings_keep_their_own_handlers() {
+    let view = detached_view();
+    let (first_hits, first_cb) = recorder();
+    let (second_hits, second_cb) = recorder();
+    let _first = view.lookup("first").bind("click", first_cb);
+    let _second = view.lookup("second").bind("click", second_cb);
+    let bind = take_queued_script(view.id());
+    view.lookup("first").unbind("click");
+    let unbind = take_queued_script(view.id());
+
+    let page = format!(
+        "{TWO_BUTTONS}{bind}\nfirst.fire(\"click\");second.fire(\"click\");first.fire(\"click\")\n\
+         {unbind}\nfirst.fire(\"click\");second.fire(\"click\")",
+    );
+    let Some(posted) = run_page(&page) else { return };
+    deliver(&posted);
+
+    assert_eq!(*first_hits.borrow(), vec![view.lookup("first"); 2]);
+    assert_eq!(*second_hits.borrow(), vec![view.lookup("second"); 2]);
+}
+
+const LIST: &str = r#"
+document.body.append(el("ul", {id: "list"}, el("li"), el("li", {}, el("b")), el("li")))
+let list = document.getElementById("list")
+"#;
+
+#[test]
+fn delegated_once_waits_for_a_matching_target() {
+    let view = detached_view();
+    let (hits, callback) = recorder();
+    let _listener = view.lookup("list")
+        .bind_with("click", BindOptions::new().delegate("li").once(), callback);
+    let bind = take_queued_script(view.id());
+
+    let page = format!(
+        "{LIST}{bind}\nlist.fire(\"click\");list.children[1].children[0].fire(\"click\");\
+         list.children[0].fire(\"click\")",
+    );
+    let Some(posted) = run_page(&page) else { return };
+    deliver(&posted);
+
+    assert_eq!(*hits.borrow(), vec![view.lookup("list").solve(vec![1])]);
+}
+
+#[test]
+fn rate_limited_once_detaches_after_the_sent_event() {
+    let view = detached_view();
+    let (hits, callback) = recorder();
+    let options = BindOptions::new().debounce(Duration::from_millis(10)).once();
+    let _listener = view.lookup("list").bind_with("click", options, callback);
+    let bind = take_queued_script(view.id());
+
+    // the burst is sent as one event, the clicks after it find no listener
+    let page = format!(
+        "{LIST}{bind}\nfor (let i = 0; i < 3; i++) list.fire(\"click\", {{detail: i}})\n\
+         setTimeout(() => list.fire(\"click\", {{detail: 3}}), 50)\n\
+         setTimeout(() => list.fire(\"click\", {{detail: 4}}), 100)",
+    );
+    let Some(posted) = run_page(&page) else { return };
+    assert_eq!(posted.len(), 1);
+    assert!(posted[0].contains("\ndetail\n2"), "{:?}", posted[0]);
+    deliver(&posted);
+
+    assert_eq!(hits.borrow().len(), 1);
+}
+
+#[test]
+fn handles_of_gone_elements_are_forgotten() {
+    let page = r#"
+let size = () => window.ipc.postMessage("size " + __lk_handles.size)
+document.body.append(el("div", {"data-jo": "1"}, el("p", {"data-jo": "2"}), el("p", {"data-jo": "3"})))
+_lk_scan(document.body)
+size()
+document.body.children[0].children[1].remove()
+_lk_scan(document.body)
+size()
+document.body.replaceChildren(el("div", {"data-jo": "4"}))
+_lk_scan(document.body)
+size()
+// an element collected without a rescan is dropped on lookup
+_lk_pin(el("p"), "5")
+setTimeout(() => {
+    gc()
+    window.ipc.postMessage("lookup " + _lk_h("5") + " " + __lk_handles.has("5"))
+}, 0)
+"#;
+    let Some(posted) = run_page(page) else { return };
+    assert_eq!(posted, ["size 3", "size 2", "size 1", "lookup undefined false"]);
+}
+
+#[test]
+fn quoted_values_reach_the_page_verbatim() {
+    let view = detached_view();
+    let nasty = "a\"b'c\\d</script>\n\u{2028}é";
+    view.lookup(nasty).set("title", nasty);
+    view.lookup(nasty).set_style("--x", nasty);
+    let (hits, callback) = recorder();
+    let _listener = view.lookup(nasty).bind("click", callback);
+    let script = take_queued_script(view.id());
+
+    let page = format!(
+        "document.body.append(el(\"p\", {{id: {nasty:?}}}))\n{script}\n\
+         let p = document.body.children[0]\n\
+         window.ipc.postMessage(\"@title \" + p.title)\n\
+         window.ipc.postMessage(\"@style \" + p.style[\"--x\"])\n\
+         p.fire(\"click\")",
+    );
+    let Some(posted) = run_page(&page) else { return };
+    assert_eq!(posted[..2], [format!("@title {nasty}"), format!("@style {nasty}")]);
+    deliver(&posted[2..]);
+
+    assert_eq!(*hits.borrow(), vec![view.lookup(nasty)]);
+}
+
+#[test]
+fn throwing_script_does_not_skip_the_reply() {
+    let view = detached_view();
+    let flushed = Rc::new(RefCell::new(false));
+    let sink = flushed.clone();
+    view.lookup("missing").set("title", "x");
+    view.flushed(move |_| *sink.borrow_mut() = true);
+    view.lookup("missing").set_style("color", "red");
+    let script = take_queued_script(view.id());
+
+    let Some(posted) = run_page(&script) else { return };
+    assert_eq!(posted.len(), 1);
+    let (head, body) = posted[0].split_once('\n').unwrap();
+    resolve_reply(head.strip_prefix("@reply ").unwrap().parse().unwrap(), body.to_string());
+
+    assert!(*flushed.borrow());
+}
+
+/// Compare evaluating many small scripts one by one with evaluating them coalesced.
+///
+/// Remarks:
+/// * Run with `cargo test -p joestar -- --ignored --nocapture coalescing`.
+/// * Node.js only shows the parsing and setup cost of each evaluation,
+///   in a WebView every evaluation is also an IPC message to the page.
+/// * The coalesced script is also run without its `try` blocks, to show what isolating errors costs.
+#[test]
+#[ignore]
+fn coalescing_cost() {
+    const UPDATES: usize = 10_000;
+    let view = detached_view();
+    let scripts: Vec<String> = (0..UPDATES).map(|i| {
+        view.root().solve(vec![i % 100]).set_style("width", &format!("{i}px"));
+        take_queued_script(view.id())
+    }).collect();
+    for i in 0..UPDATES {
+        view.root().solve(vec![i % 100]).set_style("width", &format!("{i}px"));
+    }
+    let coalesced = take_queued_script(view.id());
+    let unguarded = coalesced.replace("try{\n", "").replace("\n}catch(e){console.error(e)}", "");
+
+    // each round appends a distinct comment, so no evaluation reuses cached code
+    let page = format!(
+        "document.body.append(el(\"div\", {{}}, ...Array.from({{length: 100}}, () => el(\"p\"))))\n\
+         const vm = require(\"vm\")\n\
+         let time = f => {{ let start = process.hrtime.bigint(); f(); return Number(process.hrtime.bigint() - start) / 1e6 }}\n\
+         let run = (s, round) => vm.runInThisContext(s + \"\\n// \" + round)\n\
+         for (let round = 0; round < 5; round++) {{\n\
+             let separate = time(() => [{separate}].forEach(s => run(s, round)))\n\
+             let coalesced = time(() => run({coalesced}, round))\n\
+             let unguarded = time(() => run({unguarded}, round))\n\
+             window.ipc.postMessage(`one by one ${{separate.toFixed(1)}}ms, \
+             coalesced ${{coalesced.toFixed(1)}}ms, without try ${{unguarded.toFixed(1)}}ms`)\n\
+         }}",
+        separate = scripts.iter().map(|s| js_string(s)).collect::<Vec<_>>().join(","),
+        coalesced = js_string(&coalesced),
+        unguarded = js_string(&unguarded),
+    );
+    let Some(posted) = run_page(&page) else { return };
+    for line in posted {
+        println!("{UPDATES} style updates: {line}");
+    }
+}
+
+/// A list of 10k items with multibyte text, over `FILL_CHUNK_LEN` once serialized.
+fn large_model() -> Model {
+    Model::new("ul").children((0..10_000).map(|i| {
+        Model::new("li")
+            .attr("title", format!("{i} \"é\""))
+            .text(format!("{i} {}", "é🦀ü—\u{2028}<&>".repeat(8)))
+    }).collect())
+}
+
+/// The serializer replaced by `Model::write_html`, kept to compare with.
+///
+/// Remarks:
+/// * Every piece goes through its own `format!` and every element returns its own `String`.
+/// * Nothing is escaped, as it wasn't back then.
+mod old_serializer {
+    use crate::api::{Model, Node};
+
+    fn attrs_string<'a>(attrs: impl Iterator<Item = (&'a str, Option<&'a str>)>) -> String {
+        let mut attrs_string = String::new();
+        for (key, val) in attrs {
+            attrs_string.push_str(&format!("{}=\"{}\" ", key, val.unwrap_or_default()));
+        }
+        attrs_string
+    }
+
+    fn style_string<'a>(style: impl Iterator<Item = (&'a str, &'a str)>) -> String {
+        let mut style_string = String::new();
+        for (key, val) in style {
+            style_string.push_str(&format!("{}: {}; ", key, val));
+        }
+        style_string
+    }
+
+    pub(super) fn html_string(model: &Model) -> String {
+        let mut result = String::new();
+        result.push_str(&format!("<{}", model.tag()));
+        if let Some(id) = model.get_id() {
+            result.push_str(&format!(" id=\"{}\"", id));
+        }
+        if model.attrs().next().is_some() {
+            result.push_str(&format!(" {}", attrs_string(model.attrs())));
+        }
+        if model.styles().next().is_some() {
+            result.push_str(&format!(" style=\"{}\"", style_string(model.styles())));
+        }
+        result.push('>');
+        for node in model.child_nodes() {
+            match node {
+                Node::Element(child) => result.push_str(&html_string(child)),
+                Node::Text(text) | Node::Comment(text) => result.push_str(text),
+            }
+        }
+        result.push_str(&format!("</{}>", model.tag()));
+        result
+    }
+}
+
+/// Compare serializing 10k nodes with the old serializer and with `write_html`.
+///
+/// Remarks:
+/// * Run with `cargo test -p joestar --release -- --ignored --nocapture serializer_cost`.
+/// * The best of several rounds is reported, the first one also warms up the allocator.
+/// * The plain model has nothing to escape, so both serializers write about the same bytes for it.
+#[test]
+#[ignore]
+fn serializer_cost() {
+    const ROUNDS: usize = 20;
+    let plain = Model::new("ul").children((0..10_000).map(|i| {
+        Model::new("li")
+            .id(format!("item-{i}"))
+            .attr("title", format!("item {i}"))
+            .style("width", format!("{i}px"))
+            .text(format!("item {i}"))
+    }).collect());
+    let best = |f: &mut dyn FnMut() -> usize| {
+        (0..ROUNDS).map(|_| {
+            let start = Instant::now();
+            let len = f();
+            (start.elapsed(), len)
+        }).min().unwrap()
+    };
+    for (name, model) in [("plain", plain), ("multibyte", large_model())] {
+        let (old, old_len) = best(&mut || old_serializer::html_string(&model).len());
+        let (new, new_len) = best(&mut || model.to_html().len());
+        let mut buffer = String::new();
+        let (reused, _) = best(&mut || {
+            buffer.clear();
+            model.write_html(&mut buffer).unwrap();
+            buffer.len()
+        });
+        println!("10k {name} nodes, best of {ROUNDS}:");
+        println!("  old html_string: {old:?} for {old_len} bytes");
+        println!("  to_html: {new:?} for {new_len} bytes");
+        println!("  write_html into a reused buffer: {reused:?}");
+    }
+}
+
+/// Send markup to the bridge in pieces of at most `max` bytes and get what it assembles.
+fn reassemble(markup: &str, max: usize) -> Option<String> {
+    let mut page = chunk_scripts(markup, max).collect::<Vec<_>>().join("\n");
+    page.push_str("\nwindow.ipc.postMessage(_lk_take_chunks())");
+    run_page(&page).map(|mut posted| posted.pop().unwrap())
+}
+
+#[test]
+fn large_fill_is_reassembled_exactly() {
+    let markup = large_model().to_html();
+    assert!(markup.len() > FILL_CHUNK_LEN);
+    assert!(chunk_scripts(&markup, FILL_CHUNK_LEN).count() > 1);
+    let Some(assembled) = reassemble(&markup, FILL_CHUNK_LEN) else { return };
+    assert!(assembled == markup, "the markup changed on its way");
+
+    // small pieces cut through every multibyte character
+    let markup = Model::new("p").text("aé🦀\u{2028}ü—\"<>".repeat(50)).to_html();
+    for max in [1, 2, 3, 5, 7] {
+        assert_eq!(reassemble(&markup, max).unwrap(), markup);
+    }
+}
+
+/// Measure filling 10k nodes at several chunk sizes.
+///
+/// Remarks:
+/// * Run with `cargo test -p joestar --release -- --ignored --nocapture fill_cost`.
+/// * Node.js shows the cost of evaluating the pieces and joining them in the page,
+///   in a WebView every piece is also an IPC message to the page.
+#[test]
+#[ignore]
+fn fill_cost() {
+    let model = large_model();
+    let start = Instant::now();
+    let markup = model.to_html();
+    println!("10k nodes: {} bytes of markup serialized in {:?}", markup.len(), start.elapsed());
+    for max in [1 << 16, 1 << 18, FILL_CHUNK_LEN, 1 << 22] {
+        let start = Instant::now();
+        let scripts: Vec<_> = chunk_scripts(&markup, max).collect();
+        let built = start.elapsed();
+        let largest = scripts.iter().map(String::len).max().unwrap();
+        let page = format!(
+            "const vm = require(\"vm\")\n\
+             let start = process.hrtime.bigint()\n\
+             for (let s of [{scripts}]) vm.runInThisContext(s)\n\
+             let length = _lk_take_chunks().length\n\
+             window.ipc.postMessage(`${{Number(process.hrtime.bigint() - start) / 1e6}}ms for ${{length}} UTF-16 units`)",
+            scripts = scripts.iter().map(|s| js_string(s)).collect::<Vec<_>>().join(","),
+        );
+        let Some(posted) = run_page(&page) else { return };
+        println!(
+            "pieces of {max} bytes: {} scripts, largest {largest} bytes, built in {built:?}, evaluated in {}",
+            scripts.len(), posted[0],
+        );
+    }
+}
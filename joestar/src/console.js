@@ -0,0 +1,28 @@
+(function () {
+    let levels = {
+        error: "error",
+        warn: "warn",
+        info: "info",
+        log: "info",
+        debug: "debug",
+        trace: "trace"
+    }
+    for (let method in levels) {
+        let original = console[method]
+        console[method] = function (...args) {
+            let text = args.map(function (arg) {
+                if (typeof arg === "string") return arg
+                try {
+                    return JSON.stringify(arg)
+                } catch (e) {
+                    return String(arg)
+                }
+            }).join(" ")
+            window.ipc.postMessage("@log " + levels[method] + "\n" + text)
+            original.apply(console, args)
+        }
+    }
+    window.addEventListener("error", function (e) {
+        window.ipc.postMessage("@log error\n" + e.message + " (" + e.filename + ":" + e.lineno + ")")
+    })
+})()
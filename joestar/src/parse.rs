@@ -0,0 +1,530 @@
+use std::fmt::{Display, Formatter};
+use std::mem;
+
+use crate::api::*;
+
+/// Error of parsing HTML into Models.
+///
+/// Remarks:
+/// * The position is the byte offset in the input where the problem was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseHtmlError {
+    position: usize,
+    reason: &'static str,
+}
+
+impl ParseHtmlError {
+    /// Get the byte offset in the input where the problem was found.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
+
+impl Display for ParseHtmlError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid HTML at byte {}: {}", self.position, self.reason)
+    }
+}
+
+impl std::error::Error for ParseHtmlError {}
+
+impl Model {
+    /// Parse HTML with a single root element into a Model.
+    ///
+    /// Remarks:
+    /// * A doctype, comments and whitespace around the root element are ignored.
+    /// * The `id` and `style` attributes are mapped onto the ID and the styles of the Model.
+    /// * Elements left open are closed at the end of their parent, like browsers do.
+    /// * See `parse_html_fragment` for the details of what is accepted.
+    pub fn parse_html(html: &str) -> Result<Model, ParseHtmlError> {
+        let mut roots = Model::parse_html_fragment(html)?;
+        match roots.len() {
+            1 => Ok(roots.pop().unwrap()),
+            0 => Err(ParseHtmlError { position: html.len(), reason: "no root element" }),
+            _ => Err(ParseHtmlError { position: 0, reason: "more than one root element" }),
+        }
+    }
+
+    /// Parse HTML with any number of top-level elements into Models.
+    ///
+    /// Remarks:
+    /// * Tag and attribute names are lowercased, character references are decoded.
+    ///     * Inside `svg` and `math`, names keep their case, e.g. `viewBox` or `linearGradient`.
+    /// * Void elements like `input` need no end tag, the content of `script`, `style`,
+    ///   `textarea` and `title` is kept as text outside of `svg` and `math`.
+    /// * Text outside of any element is only allowed if it is whitespace.
+    /// * An end tag without a matching open element is an error.
+    pub fn parse_html_fragment(html: &str) -> Result<Vec<Model>, ParseHtmlError> {
+        let mut parser = Parser {
+            html,
+            pos: 0,
+            stack: vec![],
+            roots: vec![],
+        };
+        parser.parse()?;
+        Ok(parser.roots)
+    }
+}
+
+/// Elements whose content is text up to their end tag.
+const RAW_TEXT_ELEMENTS: [&str; 4] = ["script", "style", "textarea", "title"];
+
+struct Parser<'a> {
+    html: &'a str,
+    pos: usize,
+    /// Open elements along with their tag names, innermost last.
+    stack: Vec<(String, Model)>,
+    roots: Vec<Model>,
+}
+
+impl<'a> Parser<'a> {
+    fn parse(&mut self) -> Result<(), ParseHtmlError> {
+        while self.pos < self.html.len() {
+            let rest = self.rest();
+            if rest.starts_with("<!--") {
+                self.parse_comment()?;
+            } else if rest.starts_with("</") {
+                self.parse_end_tag()?;
+            } else if rest.starts_with("<!") || rest.starts_with("<?") {
+                self.skip_declaration()?;
+            } else if rest.starts_with('<') && rest[1..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+                self.parse_start_tag()?;
+            } else {
+                let first = rest.chars().next().unwrap().len_utf8();
+                let end = rest[first..].find('<').map_or(rest.len(), |i| i + first);
+                let text = decode_references(&rest[..end]);
+                self.push_text(text)?;
+                self.pos += end;
+            }
+        }
+        while !self.stack.is_empty() {
+            self.close_innermost();
+        }
+        Ok(())
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.html[self.pos..]
+    }
+
+    fn error(&self, reason: &'static str) -> ParseHtmlError {
+        ParseHtmlError { position: self.pos, reason }
+    }
+
+    fn parse_comment(&mut self) -> Result<(), ParseHtmlError> {
+        let body = &self.rest()[4..];
+        let end = body.find("-->").ok_or(self.error("unterminated comment"))?;
+        let comment = body[..end].to_string();
+        self.pos += 4 + end + 3;
+        if let Some((_, parent)) = self.stack.last_mut() {
+            *parent = mem::replace(parent, Model::new("")).comment(comment);
+        }
+        Ok(())
+    }
+
+    fn skip_declaration(&mut self) -> Result<(), ParseHtmlError> {
+        let end = self.rest().find('>').ok_or(self.error("unterminated declaration"))?;
+        self.pos += end + 1;
+        Ok(())
+    }
+
+    fn parse_end_tag(&mut self) -> Result<(), ParseHtmlError> {
+        let start = self.pos;
+        self.pos += 2;
+        let name = self.take_name();
+        self.skip_whitespace();
+        if !self.rest().starts_with('>') {
+            return Err(self.error("malformed end tag"));
+        }
+        self.pos += 1;
+        let depth = self.stack.iter().rposition(|(tag, _)| tag.eq_ignore_ascii_case(&name))
+            .ok_or(ParseHtmlError { position: start, reason: "end tag without open element" })?;
+        while self.stack.len() > depth {
+            self.close_innermost();
+        }
+        Ok(())
+    }
+
+    fn parse_start_tag(&mut self) -> Result<(), ParseHtmlError> {
+        self.pos += 1;
+        let mut name = self.take_name();
+        let foreign = self.in_foreign_content() || is_foreign_root(&name);
+        if !foreign || is_foreign_root(&name) {
+            name.make_ascii_lowercase();
+        }
+        let mut model = Model::new(name.as_str());
+        let mut seen = Vec::new();
+        let self_closing = loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            if rest.is_empty() {
+                return Err(self.error("unterminated start tag"));
+            } else if rest.starts_with("/>") {
+                self.pos += 2;
+                break true;
+            } else if rest.starts_with('>') {
+                self.pos += 1;
+                break false;
+            } else if rest.starts_with('/') {
+                self.pos += 1;
+                continue;
+            }
+            let mut key = self.take_name();
+            if key.is_empty() {
+                return Err(self.error("malformed attribute"));
+            }
+            if !foreign {
+                key.make_ascii_lowercase();
+            }
+            self.skip_whitespace();
+            let value = if self.rest().starts_with('=') {
+                self.pos += 1;
+                self.skip_whitespace();
+                Some(self.take_value()?)
+            } else {
+                None
+            };
+            // the first of duplicate attributes wins, as in browsers
+            if seen.contains(&key) {
+                continue;
+            }
+            seen.push(key.clone());
+            model = match (key.as_str(), value) {
+                ("id", Some(id)) => model.id(id),
+                ("style", Some(style)) => parse_style(model, &style),
+                (_, Some(value)) => model.attr(key, value),
+                (_, None) => model.attr(key, true),
+            };
+        };
+        if self_closing || is_void_element(&name) {
+            return self.push_node(Node::Element(model));
+        }
+        if !foreign && RAW_TEXT_ELEMENTS.contains(&name.as_str()) {
+            let rest = self.rest();
+            let end = find_end_tag(rest, &name).ok_or(self.error("unterminated raw text element"))?;
+            let text = &rest[..end];
+            if !text.is_empty() {
+                model = if name == "textarea" || name == "title" {
+                    model.text(decode_references(text))
+                } else {
+                    model.text(text)
+                };
+            }
+            self.pos += end;
+            self.stack.push((name, model));
+            return self.parse_end_tag();
+        }
+        self.stack.push((name, model));
+        Ok(())
+    }
+
+    fn take_name(&mut self) -> String {
+        let rest = self.rest();
+        let end = rest.find(|c: char| c.is_whitespace() || matches!(c, '/' | '>' | '='))
+            .unwrap_or(rest.len());
+        self.pos += end;
+        rest[..end].to_string()
+    }
+
+    /// Whether the innermost open element is inside an `svg` or `math` element.
+    fn in_foreign_content(&self) -> bool {
+        self.stack.iter().any(|(tag, _)| is_foreign_root(tag))
+    }
+
+    fn take_value(&mut self) -> Result<String, ParseHtmlError> {
+        let rest = self.rest();
+        let (raw, len) = match rest.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let end = rest[1..].find(quote).ok_or(self.error("unterminated attribute value"))?;
+                (&rest[1..end + 1], end + 2)
+            }
+            _ => {
+                let end = rest.find(|c: char| c.is_whitespace() || c == '>').unwrap_or(rest.len());
+                (&rest[..end], end)
+            }
+        };
+        self.pos += len;
+        Ok(decode_references(raw))
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn push_text(&mut self, text: String) -> Result<(), ParseHtmlError> {
+        if self.stack.is_empty() {
+            if text.trim().is_empty() {
+                return Ok(());
+            }
+            return Err(self.error("text outside of any element"));
+        }
+        self.push_node(Node::Text(text))
+    }
+
+    fn push_node(&mut self, node: Node) -> Result<(), ParseHtmlError> {
+        match (self.stack.last_mut(), node) {
+            (Some((_, parent)), node) => *parent = mem::replace(parent, Model::new("")).node(node),
+            (None, Node::Element(model)) => self.roots.push(model),
+            (None, _) => {}
+        }
+        Ok(())
+    }
+
+    fn close_innermost(&mut self) {
+        if let Some((_, model)) = self.stack.pop() {
+            let _ = self.push_node(Node::Element(model));
+        }
+    }
+}
+
+/// Whether an element starts foreign content, whose names keep their case.
+fn is_foreign_root(name: &str) -> bool {
+    name.eq_ignore_ascii_case("svg") || name.eq_ignore_ascii_case("math")
+}
+
+/// Find the end tag of a raw text element, case-insensitively.
+fn find_end_tag(html: &str, name: &str) -> Option<usize> {
+    let lower = html.to_ascii_lowercase();
+    let pattern = format!("</{}", name);
+    let mut from = 0;
+    while let Some(index) = lower[from..].find(&pattern) {
+        let index = from + index;
+        let after = &lower[index + pattern.len()..];
+        if after.starts_with(|c: char| c.is_whitespace() || c == '>' || c == '/') {
+            return Some(index);
+        }
+        from = index + pattern.len();
+    }
+    None
+}
+
+/// Set the declarations of an inline style as styles of the Model.
+///
+/// Remarks:
+/// * Semicolons inside quotes or parentheses don't end a declaration, e.g. in `url(data:...;base64,...)`.
+fn parse_style(mut model: Model, style: &str) -> Model {
+    for declaration in split_declarations(style) {
+        if let Some((key, val)) = declaration.split_once(':') {
+            let key = key.trim();
+            if !key.is_empty() {
+                model = model.style(key, val.trim());
+            }
+        }
+    }
+    model
+}
+
+/// Split an inline style on the semicolons outside of quotes and parentheses.
+fn split_declarations(style: &str) -> Vec<&str> {
+    let mut declarations = Vec::new();
+    let mut quote = None;
+    let mut depth = 0usize;
+    let mut start = 0;
+    let mut chars = style.char_indices();
+    while let Some((index, c)) = chars.next() {
+        match (quote, c) {
+            (Some(_), '\\') => {
+                chars.next();
+            }
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '(') => depth += 1,
+            (None, ')') => depth = depth.saturating_sub(1),
+            (None, ';') if depth == 0 => {
+                declarations.push(&style[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    declarations.push(&style[start..]);
+    declarations
+}
+
+/// Decode the character references of text or an attribute value.
+///
+/// Remarks:
+/// * Numeric references and the common named ones are decoded, others are kept as they are.
+fn decode_references(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find('&') {
+        decoded.push_str(&rest[..index]);
+        rest = &rest[index..];
+        let end = rest[1..].find(|c: char| !c.is_ascii_alphanumeric() && c != '#')
+            .map_or(rest.len(), |i| i + 1);
+        let name = &rest[1..end];
+        let c = if let Some(number) = name.strip_prefix('#') {
+            if let Some(hex) = number.strip_prefix(['x', 'X']) {
+                u32::from_str_radix(hex, 16).ok()
+            } else {
+                number.parse::<u32>().ok()
+            }.and_then(char::from_u32)
+        } else {
+            match name {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some('\u{a0}'),
+                _ => None,
+            }
+        };
+        match c {
+            Some(c) => {
+                decoded.push(c);
+                rest = &rest[end..];
+                if rest.starts_with(';') {
+                    rest = &rest[1..];
+                }
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(html: &str) -> String {
+        Model::parse_html(html).unwrap().to_html()
+    }
+
+    fn error(html: &str) -> (usize, &'static str) {
+        let err = Model::parse_html(html).unwrap_err();
+        (err.position, err.reason)
+    }
+
+    #[test]
+    fn multibyte_text() {
+        assert_eq!(parse("<p>é</p>"), "<p>é</p>");
+        assert_eq!(parse("<p>日本語<b>🦀</b>é&amp;ü</p>"), "<p>日本語<b>🦀</b>é&amp;ü</p>");
+        assert_eq!(parse("<p title=\"é\">a<br>ü</p>"), "<p title=\"é\">a<br>ü</p>");
+        let expected = Model::new("p").text("é").child(Model::new("b").text("x"));
+        assert_eq!(Model::parse_html("<p>é<b>x</b>"), Ok(expected));
+    }
+
+    #[test]
+    fn unclosed_elements_close_with_their_parent() {
+        assert_eq!(parse("<ul><li>a<li>b"), "<ul><li>a<li>b</li></li></ul>");
+        assert_eq!(parse("<div><p><b>x</div>"), "<div><p><b>x</b></p></div>");
+        assert_eq!(
+            parse("<div><input type=text><img src=a.png/></div>"),
+            r#"<div><input type="text"><img src="a.png/"></div>"#,
+        );
+        assert_eq!(parse("<div><span/>x</div>"), "<div><span></span>x</div>");
+    }
+
+    #[test]
+    fn raw_text_elements() {
+        let script = Model::parse_html(r#"<script>if (a < b && c) x = "</div>"</SCRIPT >"#).unwrap();
+        assert_eq!(script.child_nodes(), [Node::Text(r#"if (a < b && c) x = "</div>""#.into())]);
+        let style = Model::parse_html("<style>a > b { color: red }</style>").unwrap();
+        assert_eq!(style.to_html(), "<style>a > b { color: red }</style>");
+        let textarea = Model::parse_html("<textarea>&lt;b&gt; <i></textarea>").unwrap();
+        assert_eq!(textarea.child_nodes(), [Node::Text("<b> <i>".into())]);
+        assert_eq!(Model::parse_html("<title></title>").unwrap().child_nodes(), []);
+        assert_eq!(error("<div><script>x</div>"), (13, "unterminated raw text element"));
+    }
+
+    #[test]
+    fn character_references() {
+        let model = Model::parse_html(concat!(
+            r#"<p title="a&quot;b&amp;c">"#,
+            r#"&amp;&lt;&gt;&quot;&apos;&nbsp;&#65;&#x1F980;&#X41; &unknown; & &#xZZ; &#1114112;</p>"#,
+        )).unwrap();
+        assert_eq!(model.get_attr("title"), Some("a\"b&c"));
+        assert_eq!(
+            model.child_nodes(),
+            [Node::Text("&<>\"'\u{a0}A🦀A &unknown; & &#xZZ; &#1114112;".into())],
+        );
+        assert_eq!(Model::parse_html("<p>&amp</p>").unwrap().child_nodes(), [Node::Text("&".into())]);
+    }
+
+    #[test]
+    fn error_positions() {
+        assert_eq!(error(""), (0, "no root element"));
+        assert_eq!(error("<!-- only -->"), (13, "no root element"));
+        assert_eq!(error("<p></p><p></p>"), (0, "more than one root element"));
+        assert_eq!(error("<div></span></div>"), (5, "end tag without open element"));
+        assert_eq!(error("<div><!-- x"), (5, "unterminated comment"));
+        assert_eq!(error("<p title=\"x>"), (9, "unterminated attribute value"));
+        assert_eq!(error("<div"), (4, "unterminated start tag"));
+        assert_eq!(error("<p></p x>"), (7, "malformed end tag"));
+        assert_eq!(error("<p =x></p>"), (3, "malformed attribute"));
+        assert_eq!(error("é<p></p>"), (0, "text outside of any element"));
+        assert_eq!(error("<!DOCTYPE html"), (0, "unterminated declaration"));
+        let err = Model::parse_html("<a></b>").unwrap_err();
+        assert_eq!(err.position(), 3);
+        assert_eq!(err.to_string(), "invalid HTML at byte 3: end tag without open element");
+    }
+
+    #[test]
+    fn foreign_names_keep_their_case() {
+        let html = concat!(
+            r#"<DIV CLASS="icon"><svg viewBox="0 0 1 1" preserveAspectRatio="none">"#,
+            r#"<defs><linearGradient id="g" gradientUnits="userSpaceOnUse"/>"#,
+            r#"<clipPath id="c"></CLIPPATH></defs>"#,
+            r#"<style>a<b></b></style></svg><Span>x</span></DIV>"#,
+        );
+        assert_eq!(parse(html), concat!(
+            r#"<div class="icon"><svg viewBox="0 0 1 1" preserveAspectRatio="none">"#,
+            r#"<defs><linearGradient id="g" gradientUnits="userSpaceOnUse"></linearGradient>"#,
+            r#"<clipPath id="c"></clipPath></defs>"#,
+            r#"<style>a<b></b></style></svg><span>x</span></div>"#,
+        ));
+        assert_eq!(
+            parse(r#"<MATH><mi mathVariant="bold">x</mi></MATH>"#),
+            r#"<math><mi mathVariant="bold">x</mi></math>"#,
+        );
+    }
+
+    #[test]
+    fn style_declarations_split_outside_quotes_and_parentheses() {
+        let model = Model::parse_html(concat!(
+            r#"<p style="background: url('data:image/png;base64,AAAA'); color: red;"#,
+            r#" font-family: &quot;a;b&quot;, serif; mask: url(x;y)"></p>"#,
+        )).unwrap();
+        assert_eq!(model.styles().collect::<Vec<_>>(), [
+            ("background", "url('data:image/png;base64,AAAA')"),
+            ("color", "red"),
+            ("font-family", "\"a;b\", serif"),
+            ("mask", "url(x;y)"),
+        ]);
+    }
+
+    #[test]
+    fn first_duplicate_attribute_wins() {
+        let model = Model::parse_html(concat!(
+            r#"<p title="a" id="x" style="color: red" title="b" id="y" style="color: blue" "#,
+            r#"TITLE="c" hidden hidden="no"></p>"#,
+        )).unwrap();
+        assert_eq!(model.get_attr("title"), Some("a"));
+        assert_eq!(model.get_id(), Some("x"));
+        assert_eq!(model.styles().collect::<Vec<_>>(), [("color", "red")]);
+        assert_eq!(model.attrs().collect::<Vec<_>>(), [("title", Some("a")), ("hidden", None)]);
+    }
+
+    #[test]
+    fn serialized_models_parse_back() {
+        let model = Model::new("div").id("a\"b").attr("data-x", "<&>").attr("hidden", true)
+            .style("color", "red").style("font-family", "\"Fira Sans\"")
+            .text("1 < 2 é").comment(" note ")
+            .child(Model::new("ul").child(Model::new("li").text("🦀")).child(Model::new("li")))
+            .child(Model::new("script").text("a < b && c"))
+            .child(Model::new("input").attr("value", "x"));
+        assert_eq!(Model::parse_html(&model.to_html()), Ok(model.clone()));
+        let fragment = Model::parse_html_fragment(&model.to_html_pretty().repeat(2)).unwrap();
+        assert_eq!(fragment.len(), 2);
+        assert_eq!(fragment[0], fragment[1]);
+    }
+}
@@ -1,11 +1,13 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::{BTreeMap, HashMap};
 use std::fmt::{Debug};
 use std::os::linux::raw::stat;
+use std::panic::{AssertUnwindSafe, catch_unwind};
 use std::sync::mpsc;
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
-use log::error;
+use std::thread::JoinHandle;
+use log::{debug, error, Level, log, warn};
 use wry::application::dpi::LogicalSize;
 use wry::application::event::{Event, WindowEvent};
 use wry::application::event_loop::{ControlFlow, EventLoopProxy, EventLoopWindowTarget};
@@ -13,22 +15,42 @@ use wry::application::window::{WindowBuilder, WindowId};
 use wry::webview::{WebView, WebViewBuilder};
 
 use crate::api::*;
+use crate::app::*;
+
+/// Options of the runtime that live on the main thread.
+#[derive(Debug, Clone)]
+pub(crate) struct RtConfig {
+    pub(crate) log_console: bool,
+    pub(crate) exit_policy: ExitPolicy,
+    pub(crate) close_behavior: CloseBehavior,
+}
 
 pub(crate) struct RtState {
     pub(crate) proxy: EventLoopProxy<JoEvent>,
-    pub(crate) views: BTreeMap<usize, WebView>,
-    pub(crate) view_event_callback_map: BTreeMap<usize, BTreeMap<ViewEventKey, usize>>,
-    pub(crate) view_wid_map: BTreeMap<WindowId, usize>,
+    pub(crate) config: RtConfig,
+    pub(crate) views: BTreeMap<ViewId, WebView>,
+    pub(crate) view_event_callback_map: BTreeMap<ViewId, BTreeMap<ViewEventKey, usize>>,
+    pub(crate) view_wid_map: BTreeMap<WindowId, ViewId>,
+    /// Scripts waiting for the bridge of a View to be ready, in order of arrival.
+    pub(crate) pending_scripts: BTreeMap<ViewId, Vec<String>>,
+    pub(crate) user_thread: Option<JoinHandle<()>>,
+    pub(crate) exit_code: Option<i32>,
+    pub(crate) user_exited: bool,
 }
 
 impl RtState {
     #[inline]
-    pub(crate) fn new(proxy: EventLoopProxy<JoEvent>) -> Self {
+    pub(crate) fn new(proxy: EventLoopProxy<JoEvent>, config: RtConfig) -> Self {
         Self {
             proxy,
+            config,
             views: BTreeMap::new(),
             view_event_callback_map: BTreeMap::new(),
             view_wid_map: BTreeMap::new(),
+            pending_scripts: BTreeMap::new(),
+            user_thread: None,
+            exit_code: None,
+            user_exited: false,
         }
     }
 }
@@ -46,9 +68,9 @@ pub(crate) fn handle_wry_event(
         Event::WindowEvent { window_id, event , .. } => match event {
             WindowEvent::Resized(size) => {
                 let ord = if let Some(ord) = state.view_wid_map.get(&window_id)
-                { ord } else { return };
+                { *ord } else { return };
                 let cb_index = if let Some(cbi) = state.view_event_callback_map
-                    .get(ord).unwrap()
+                    .get(&ord).unwrap()
                     .get(&ViewEventKey::Resize)
                 { cbi } else { return };
                 let width = size.width;
@@ -56,7 +78,7 @@ pub(crate) fn handle_wry_event(
                 let cb_index = *cb_index;
                 user_dispatch(move || {
                     if let Some(cb) = Callback::get(cb_index) {
-                        cb.invoke(Agent::invalid(), HashMap::from([
+                        cb.invoke(Agent::root_of(ord), HashMap::from([
                             ("width".to_string(), width.to_string()),
                             ("height".to_string(), height.to_string()),
                         ]));
@@ -65,9 +87,9 @@ pub(crate) fn handle_wry_event(
             }
             WindowEvent::Moved(pos) => {
                 let ord = if let Some(ord) = state.view_wid_map.get(&window_id)
-                { ord } else { return };
+                { *ord } else { return };
                 let cb_index = if let Some(cbi) = state.view_event_callback_map
-                    .get(ord).unwrap()
+                    .get(&ord).unwrap()
                     .get(&ViewEventKey::Move)
                 { cbi } else { return };
                 let x = pos.x;
@@ -75,7 +97,7 @@ pub(crate) fn handle_wry_event(
                 let cb_index = *cb_index;
                 user_dispatch(move || {
                     if let Some(cb) = Callback::get(cb_index) {
-                        cb.invoke(Agent::invalid(), HashMap::from([
+                        cb.invoke(Agent::root_of(ord), HashMap::from([
                             ("x".to_string(), x.to_string()),
                             ("y".to_string(), y.to_string()),
                         ]));
@@ -84,14 +106,19 @@ pub(crate) fn handle_wry_event(
             }
             WindowEvent::CloseRequested => {
                 let ord = if let Some(ord) = state.view_wid_map.get(&window_id)
-                { ord } else { return };
+                { *ord } else { return };
                 let cb_index = if let Some(cbi) = state.view_event_callback_map
-                    .get(ord).unwrap()
+                    .get(&ord).unwrap()
                     .get(&ViewEventKey::CloseRequest)
-                { *cbi } else { return };
+                { *cbi } else {
+                    if state.config.close_behavior == CloseBehavior::Destroy {
+                        destroy_view(state, ord, control_flow);
+                    }
+                    return
+                };
                 user_dispatch(move || {
                     if let Some(cb) = Callback::get(cb_index) {
-                        cb.invoke(Agent::invalid(), HashMap::new());
+                        cb.invoke(Agent::root_of(ord), HashMap::new());
                     }
                 })
             }
@@ -131,7 +158,7 @@ pub(crate) fn handle_wry_event(
         Event::MainEventsCleared => {}
         Event::RedrawRequested(_) => {}
         Event::RedrawEventsCleared => {}
-        Event::LoopDestroyed => {}
+        Event::LoopDestroyed => handle_loop_destroyed(state),
         _ => {
             error!("Unknown event: {event:?}");
         }
@@ -146,30 +173,146 @@ pub(crate) fn handle_joestar_event(
     control_flow: &mut ControlFlow
 ) {
     match jo_event {
-        JoEvent::UserLaunch { user_init } =>
-            handle_user_launch(user_init, &state.proxy),
+        JoEvent::UserLaunch { launch } =>
+            state.user_thread = Some(handle_user_launch(launch, &state.proxy)),
         JoEvent::CreateWebView { ord, spec } =>
             handle_create_web_view(spec, ord, window_target, state),
         JoEvent::EvalScript { ord: window_id, script } => {
-            state.views.get(&window_id).unwrap()
-                .evaluate_script(&script).unwrap();
-        }
-        JoEvent::DestroyWebView { ord } => {
-            state.views.remove(&ord).unwrap();
+            if let Some(pending) = state.pending_scripts.get_mut(&window_id) {
+                pending.push(script);
+            } else if let Some(view) = state.views.get(&window_id) {
+                view.evaluate_script(&script).unwrap();
+            } else {
+                debug!("Script sent to a destroyed view: {window_id}");
+            }
         }
+        JoEvent::ViewReady { ord } => handle_view_ready(state, ord),
+        JoEvent::DestroyWebView { ord } => destroy_view(state, ord, control_flow),
         JoEvent::RegisterEvent { ord, key, cb_index } => {
+            if !state.views.contains_key(&ord) {
+                return;
+            }
             let callbacks = state.view_event_callback_map
                 .entry(ord).or_default();
             callbacks.insert(key, cb_index);
+            if key == ViewEventKey::Ready && !state.pending_scripts.contains_key(&ord) {
+                dispatch_view_event(ord, cb_index);
+            }
+        }
+        JoEvent::Terminate { code } => begin_exit(state, code, control_flow),
+        JoEvent::UserExited => {
+            state.user_exited = true;
+            state.views.clear();
+            state.view_event_callback_map.clear();
+            state.view_wid_map.clear();
+            state.pending_scripts.clear();
+            *control_flow = ControlFlow::ExitWithCode(state.exit_code.unwrap_or(0));
+        }
+    }
+}
+
+/// Destroy a WebView, exiting if it was the last one and the exit policy says so.
+///
+/// Remarks:
+/// * The runtime maps of the WebView are cleaned up as well, and the View is forgotten on user thread.
+/// * Destroying an already destroyed WebView does nothing.
+#[inline]
+pub(crate) fn destroy_view(state: &mut RtState, ord: ViewId, control_flow: &mut ControlFlow) {
+    let view = if let Some(view) = state.views.remove(&ord)
+    { view } else { return };
+    state.view_wid_map.remove(&view.window().id());
+    state.view_event_callback_map.remove(&ord);
+    state.pending_scripts.remove(&ord);
+    let exit = state.config.exit_policy == ExitPolicy::LastViewDestroyed && state.views.is_empty();
+    if !release_view(ord, exit, &mut state.exit_code) {
+        state.views.clear();
+        *control_flow = ControlFlow::ExitWithCode(0);
+    }
+}
+
+/// Forget a destroyed WebView on user thread, then ask it to exit if `exit` is set.
+///
+/// Remarks:
+/// * The View is forgotten first, so exit hooks no longer see it as alive.
+/// * Returns `false` if an exit was requested but the user thread is gone.
+fn release_view(ord: ViewId, exit: bool, exit_code: &mut Option<i32>) -> bool {
+    user_dispatch(move || forget_view(ord));
+    !exit || request_exit(exit_code, 0)
+}
+
+/// Flush the scripts buffered for a View and fire its ready event.
+///
+/// Remarks:
+/// * The page reports readiness once the bridge functions are defined.
+/// * A page reporting readiness again, e.g. after a reload, fires the event again.
+#[inline]
+pub(crate) fn handle_view_ready(state: &mut RtState, ord: ViewId) {
+    let view = if let Some(view) = state.views.get(&ord)
+    { view } else { return };
+    for script in state.pending_scripts.remove(&ord).unwrap_or_default() {
+        view.evaluate_script(&script).unwrap();
+    }
+    let cb_index = if let Some(cbi) = state.view_event_callback_map
+        .get(&ord)
+        .and_then(|callbacks| callbacks.get(&ViewEventKey::Ready))
+    { *cbi } else { return };
+    dispatch_view_event(ord, cb_index);
+}
+
+/// Invoke a View event callback without detail on the user thread.
+#[inline]
+fn dispatch_view_event(ord: ViewId, cb_index: usize) {
+    user_dispatch(move || {
+        if let Some(cb) = Callback::get(cb_index) {
+            cb.invoke(Agent::root_of(ord), HashMap::new());
         }
-        JoEvent::Terminate => {
-            *control_flow = ControlFlow::Exit;
+    })
+}
+
+/// Ask the user thread to finish its pending callbacks and exit hooks.
+///
+/// Remarks:
+/// * The loop only ends once the user thread reports back with `UserExited`.
+/// * The first requested exit code wins.
+#[inline]
+pub(crate) fn begin_exit(state: &mut RtState, code: i32, control_flow: &mut ControlFlow) {
+    if !request_exit(&mut state.exit_code, code) {
+        state.views.clear();
+        *control_flow = ControlFlow::ExitWithCode(code);
+    }
+}
+
+/// Send an exit request to user thread, unless one was sent already.
+///
+/// Remarks:
+/// * Returns `false` if the user thread is gone.
+fn request_exit(exit_code: &mut Option<i32>, code: i32) -> bool {
+    if exit_code.is_some() {
+        return true;
+    }
+    *exit_code = Some(code);
+    user_send(UserTask::Exit(code))
+}
+
+#[inline]
+pub(crate) fn handle_loop_destroyed(state: &mut RtState) {
+    state.views.clear();
+    let handle = if let Some(handle) = state.user_thread.take()
+    { handle } else { return };
+    if state.user_exited {
+        if handle.join().is_err() {
+            error!("User thread panicked while exiting");
         }
+    } else {
+        warn!("User thread is still running, detaching it");
     }
 }
 
 #[inline]
-pub(crate) fn handle_user_launch(user_init: fn(), proxy: &EventLoopProxy<JoEvent>) {
+pub(crate) fn handle_user_launch(
+    launch: UserLaunch,
+    proxy: &EventLoopProxy<JoEvent>
+) -> JoinHandle<()> {
     let proxy = proxy.clone();
     thread::spawn(move || {
         PROXY.with(move |static_proxy| {
@@ -177,17 +320,101 @@ pub(crate) fn handle_user_launch(user_init: fn(), proxy: &EventLoopProxy<JoEvent
         });
         let (tx, rx) = mpsc::channel();
         init_sender(tx);
-        user_init();
-        while let Ok(callback) = rx.recv() {
-            callback();
+        RECEIVER.with(|receiver| *receiver.borrow_mut() = Some(rx));
+        DEFAULT_SPEC.with(|spec| *spec.borrow_mut() = launch.default_spec);
+        ERROR_HOOK.with(|hook| *hook.borrow_mut() = launch.error_hook);
+        STAMP_HANDLES.with(|stamp| stamp.set(launch.stamp_handles));
+        if let Some(user_init) = launch.init {
+            run_task(user_init);
+        }
+        while let Some(task) = next_task() {
+            match task {
+                UserTask::Run(callback) => run_task(callback),
+                UserTask::Exit(code) => {
+                    for hook in EXIT_HOOKS.with(|hooks| hooks.take()) {
+                        run_guarded(move || hook(code));
+                    }
+                    break;
+                }
+            }
+        }
+        PROXY.with(|proxy| {
+            let _ = proxy.borrow().as_ref().unwrap().send_event(JoEvent::UserExited);
+        });
+    })
+}
+
+/// Take the next task of user thread, blocking until there is one.
+///
+/// Remarks:
+/// * An exit postponed by `pump_user_task` comes first.
+fn next_task() -> Option<UserTask> {
+    if let Some(code) = PENDING_EXIT.with(|exit| exit.take()) {
+        return Some(UserTask::Exit(code));
+    }
+    RECEIVER.with(|receiver| receiver.borrow().as_ref()?.recv().ok())
+}
+
+/// Run the next task of user thread from within user code, e.g. while waiting for a reply.
+///
+/// Remarks:
+/// * An exit request is postponed until control returns to the task loop.
+/// * Returns `false` if the runtime is exiting and no more tasks should be waited for.
+pub(crate) fn pump_user_task() -> bool {
+    if PENDING_EXIT.with(|exit| exit.get().is_some()) {
+        return false;
+    }
+    match next_task() {
+        Some(UserTask::Run(callback)) => {
+            run_task(callback);
+            true
+        }
+        Some(UserTask::Exit(code)) => {
+            PENDING_EXIT.with(|exit| exit.set(Some(code)));
+            false
         }
+        None => false,
+    }
+}
+
+/// Run a task of user thread and send the scripts it queued.
+fn run_task<F: FnOnce()>(f: F) {
+    run_guarded(f);
+    end_of_task();
+}
+
+/// Run a piece of user code, reporting a panic to the error hook instead of unwinding.
+fn run_guarded<F: FnOnce()>(f: F) {
+    if let Err(payload) = catch_unwind(AssertUnwindSafe(f)) {
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "unknown panic payload".to_string()
+        };
+        raise_error(Error::Panic(message));
+    }
+}
+
+/// Pass an error to the error hook, or log it if there is none.
+pub(crate) fn raise_error(error: Error) {
+    ERROR_HOOK.with(|hook| match hook.borrow().as_ref() {
+        Some(hook) => hook(&error),
+        None => error!("{error}"),
     });
 }
 
+/// Set the error hook of the current thread, for tests.
+#[cfg(test)]
+pub(crate) fn set_error_hook(hook: Option<ErrorHook>) {
+    ERROR_HOOK.with(|cur| *cur.borrow_mut() = hook);
+}
+
 #[inline]
 pub(crate) fn handle_create_web_view(
     spec: Spec,
-    ord: usize,
+    ord: ViewId,
     window_target: &EventLoopWindowTarget<JoEvent>,
     state: &mut RtState,
 ) {
@@ -195,24 +422,25 @@ pub(crate) fn handle_create_web_view(
         .with_title(spec.title)
         .with_inner_size(LogicalSize::<u32>::from(spec.size))
         .build(window_target).unwrap();
-    let web_view = WebViewBuilder::new(window).unwrap()
-        .with_html(include_str!("index.html")).unwrap()
-        .with_ipc_handler(|window, raw| {
-            let mut raw = raw.lines();
-            let head = raw.next().unwrap();
-            let mut head = head.split(">>>");
-            let path = head.next().unwrap();
-            let agent = Agent::from(path);
-            let cb_index: usize = head.next().unwrap().parse().unwrap();
-            let mut detail: HashMap<String, String> = HashMap::new();
-            while let Some(key) = raw.next() {
-                let value = raw.next().unwrap();
-                detail.insert(key.into(), value.into());
+    let mut builder = WebViewBuilder::new(window).unwrap()
+        .with_html(include_str!("index.html")).unwrap();
+    if state.config.log_console {
+        builder = builder.with_initialization_script(include_str!("console.js"));
+    }
+    let proxy = state.proxy.clone();
+    let web_view = builder
+        .with_ipc_handler(move |_, raw| {
+            if let Some(control) = raw.strip_prefix('@') {
+                handle_control_message(control, ord, &proxy);
+                return;
             }
-            if let Some(callback) = Callback::get(cb_index) {
-                user_dispatch(move || {
-                    callback.invoke(agent, detail);
-                });
+            match parse_event_message(&raw) {
+                Some((agent, cb_index, detail)) => user_dispatch(move || {
+                    if let Some(callback) = Callback::get(cb_index) {
+                        callback.invoke(agent, detail);
+                    }
+                }),
+                None => error!("Malformed event message: {raw:?}"),
             }
         })
         .build().unwrap();
@@ -220,46 +448,164 @@ pub(crate) fn handle_create_web_view(
     state.views.insert(ord, web_view);
     state.view_event_callback_map.insert(ord, BTreeMap::new());
     state.view_wid_map.insert(window_id, ord);
+    state.pending_scripts.insert(ord, Vec::new());
 }
 
-#[derive(Debug, Clone)]
+/// Parse a message sent by a DOM event listener.
+///
+/// Remarks:
+/// * The head line is `agent>>>callback index`, optionally followed by `>>>target`.
+///     * The target is either `#handle` or a path relative to the agent.
+/// * The remaining lines are pairs of detail keys and values.
+#[inline]
+pub(crate) fn parse_event_message(raw: &str) -> Option<(Agent, usize, HashMap<String, String>)> {
+    let mut raw = raw.lines();
+    let mut head = raw.next()?.split(">>>");
+    let mut agent = Agent::try_from(head.next()?).ok()?;
+    let cb_index: usize = head.next()?.parse().ok()?;
+    if let Some(target) = head.next() {
+        agent = if let Some(handle) = target.strip_prefix('#') {
+            agent.at_handle(handle.parse().ok()?)
+        } else {
+            let target = target.split(',')
+                .filter(|i| !i.is_empty())
+                .map(|i| i.parse::<usize>().ok())
+                .collect::<Option<_>>()?;
+            agent.solve(target)
+        };
+    }
+    let mut detail: HashMap<String, String> = HashMap::new();
+    while let (Some(key), Some(value)) = (raw.next(), raw.next()) {
+        detail.insert(key.into(), value.into());
+    }
+    Some((agent, cb_index, detail))
+}
+
+/// Handle a message sent by the page itself rather than by a DOM event listener.
+///
+/// Remarks:
+/// * Control messages start with `@`, followed by the kind and its arguments.
+/// * Messages concerning the runtime are forwarded to the event loop.
+#[inline]
+fn handle_control_message(control: &str, ord: ViewId, proxy: &EventLoopProxy<JoEvent>) {
+    let (head, body) = control.split_once('\n').unwrap_or((control, ""));
+    let mut head = head.split(' ');
+    match head.next() {
+        Some("log") => {
+            let level = match head.next() {
+                Some("error") => Level::Error,
+                Some("warn") => Level::Warn,
+                Some("debug") => Level::Debug,
+                Some("trace") => Level::Trace,
+                _ => Level::Info,
+            };
+            log!(target: "joestar::console", level, "{body}");
+        }
+        Some("reply") => {
+            let reply_id = if let Some(Ok(reply_id)) = head.next().map(str::parse::<u64>)
+            { reply_id } else { return error!("Malformed reply: {control}") };
+            let body = body.to_string();
+            user_dispatch(move || resolve_reply(reply_id, body));
+        }
+        Some("ready") => {
+            let _ = proxy.send_event(JoEvent::ViewReady { ord });
+        }
+        _ => error!("Unknown control message: {control}"),
+    }
+}
+
+#[derive(Debug)]
 pub(crate) enum JoEvent {
     UserLaunch {
-        user_init: fn(),
+        launch: UserLaunch,
     },
     CreateWebView {
-        ord: usize,
+        ord: ViewId,
         spec: Spec,
     },
     EvalScript {
-        ord: usize,
+        ord: ViewId,
         script: String,
     },
     DestroyWebView {
-        ord: usize,
+        ord: ViewId,
+    },
+    ViewReady {
+        ord: ViewId,
     },
     RegisterEvent {
-        ord: usize,
+        ord: ViewId,
         key: ViewEventKey,
         cb_index: usize,
     },
-    Terminate,
+    Terminate {
+        code: i32,
+    },
+    UserExited,
 }
 
+/// Work item of the user thread.
+pub(crate) enum UserTask {
+    Run(Box<dyn FnOnce()>),
+    Exit(i32),
+}
+
+pub(crate) type ExitHook = Box<dyn FnOnce(i32)>;
+
 thread_local! {
     pub(crate) static PROXY: RefCell<Option<EventLoopProxy<JoEvent>>> = RefCell::new(None);
+    pub(crate) static DEFAULT_SPEC: RefCell<Spec> = RefCell::new(Spec::default());
+    pub(crate) static STAMP_HANDLES: Cell<bool> = const { Cell::new(false) };
+    static ERROR_HOOK: RefCell<Option<ErrorHook>> = const { RefCell::new(None) };
+    pub(crate) static EXIT_HOOKS: RefCell<Vec<ExitHook>> = const { RefCell::new(Vec::new()) };
+    static RECEIVER: RefCell<Option<Receiver<UserTask>>> = const { RefCell::new(None) };
+    static PENDING_EXIT: Cell<Option<i32>> = const { Cell::new(None) };
 }
 
-static mut SENDER: Option<Sender<Box<dyn FnOnce()>>> = None;
+static mut SENDER: Option<Sender<UserTask>> = None;
 
-fn init_sender(sender: Sender<Box<dyn FnOnce()>>) {
+fn init_sender(sender: Sender<UserTask>) {
     unsafe {
         SENDER = Some(sender);
     }
 }
 
+fn user_send(task: UserTask) -> bool {
+    let sent = unsafe {
+        SENDER.as_ref().is_some_and(|sender| sender.send(task).is_ok())
+    };
+    if !sent {
+        debug!("User thread is gone, dropping a task");
+    }
+    sent
+}
+
 fn user_dispatch<F: FnOnce() + 'static>(f: F) {
-    unsafe {
-        SENDER.as_ref().unwrap().send(Box::new(f)).unwrap();
+    user_send(UserTask::Run(Box::new(f)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::detached_view;
+
+    #[test]
+    fn destroyed_view_is_forgotten_before_exit() {
+        let (tx, rx) = mpsc::channel();
+        init_sender(tx);
+        let view = detached_view();
+        let mut exit_code = None;
+        assert!(release_view(view.id(), true, &mut exit_code));
+        assert_eq!(exit_code, Some(0));
+        match rx.try_recv() {
+            Ok(UserTask::Run(forget)) => forget(),
+            _ => panic!("expected the View to be forgotten first"),
+        }
+        assert!(!view.is_alive());
+        assert!(matches!(rx.try_recv(), Ok(UserTask::Exit(0))));
+        // exiting already, so only the forget is sent
+        assert!(release_view(detached_view().id(), true, &mut exit_code));
+        assert!(matches!(rx.try_recv(), Ok(UserTask::Run(_))));
+        assert!(rx.try_recv().is_err());
     }
-}
\ No newline at end of file
+}
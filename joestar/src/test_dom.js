@@ -0,0 +1,149 @@
+// Minimal DOM for running the bridge of index.html in Node.js during tests.
+// Only what the bridge touches is provided, events are dispatched by hand with `fire`.
+let __posted = []
+
+class Event {
+    constructor(type, target, fields) {
+        Object.defineProperty(this, "_stopped", {value: false, writable: true})
+        this.type = type
+        this.target = target
+        Object.assign(this, fields)
+    }
+
+    preventDefault() {}
+
+    stopPropagation() {
+        this._stopped = true
+    }
+
+    stopImmediatePropagation() {
+        this._stopped = true
+    }
+}
+
+class Element {
+    constructor(tag) {
+        this.tagName = tag.toUpperCase()
+        this.id = ""
+        this.className = ""
+        this.dataset = {}
+        this.children = []
+        this.parentElement = null
+        this.style = {setProperty(key, value) { this[key] = value }}
+        Object.defineProperty(this, "_listeners", {value: []})
+    }
+
+    get isConnected() {
+        let elem = this
+        while (elem.parentElement !== null) elem = elem.parentElement
+        return elem === document.documentElement
+    }
+
+    setAttribute(key, value) {
+        if (key === "id") this.id = value
+        else if (key === "class") this.className = value
+        else if (key.startsWith("data-")) this.dataset[key.slice(5)] = value
+        else this[key] = value
+    }
+
+    append(...children) {
+        for (let child of children) {
+            child.remove()
+            child.parentElement = this
+            this.children.push(child)
+        }
+    }
+
+    remove() {
+        if (this.parentElement === null) return
+        let siblings = this.parentElement.children
+        siblings.splice(siblings.indexOf(this), 1)
+        this.parentElement = null
+    }
+
+    replaceChildren(...children) {
+        for (let child of [...this.children]) child.remove()
+        this.append(...children)
+    }
+
+    contains(other) {
+        for (let elem = other; elem !== null; elem = elem.parentElement) {
+            if (elem === this) return true
+        }
+        return false
+    }
+
+    matches(selector) {
+        return selector.split(",").map(s => s.trim()).some(s => {
+            if (s.startsWith("#")) return this.id === s.slice(1)
+            if (s.startsWith(".")) return this.className.split(" ").includes(s.slice(1))
+            if (s.startsWith("[data-")) return this.dataset[s.slice(6, -1)] !== undefined
+            return this.tagName === s.toUpperCase()
+        })
+    }
+
+    closest(selector) {
+        for (let elem = this; elem !== null; elem = elem.parentElement) {
+            if (elem.matches(selector)) return elem
+        }
+        return null
+    }
+
+    querySelectorAll(selector) {
+        return this.descendants().filter(elem => elem.matches(selector))
+    }
+
+    descendants() {
+        return this.children.flatMap(child => [child, ...child.descendants()])
+    }
+
+    addEventListener(type, func, opts) {
+        this._listeners.push({type, func, capture: !!opts.capture, once: !!opts.once})
+    }
+
+    removeEventListener(type, func, opts) {
+        let index = this._listeners.findIndex(l =>
+            l.type === type && l.func === func && l.capture === !!opts.capture)
+        if (index >= 0) this._listeners.splice(index, 1)
+    }
+
+    // dispatch an event at this element, bubbling up to the root
+    fire(type, fields) {
+        let e = new Event(type, this, fields || {})
+        for (let elem = this; elem !== null && !e._stopped; elem = elem.parentElement) {
+            for (let listener of [...elem._listeners]) {
+                if (listener.type !== type || !elem._listeners.includes(listener)) continue
+                if (listener.once) elem.removeEventListener(type, listener.func, listener)
+                listener.func(e)
+            }
+        }
+    }
+}
+
+// build an element, e.g. el("button", {id: "ok"}, el("span"))
+function el(tag, attrs, ...children) {
+    let elem = new Element(tag)
+    for (let key in attrs || {}) elem.setAttribute(key, attrs[key])
+    elem.append(...children)
+    return elem
+}
+
+let document = {
+    documentElement: el("html", {}, el("body")),
+    get body() {
+        return this.documentElement.children[0]
+    },
+    getElementById(id) {
+        return this.documentElement.descendants().find(elem => elem.id === id) || null
+    }
+}
+
+let window = globalThis
+window.ipc = {postMessage(message) { __posted.push(message) }}
+window.requestAnimationFrame = func => setTimeout(func, 16)
+// written once nothing is left to run, as output written on exit may be cut short
+process.on("beforeExit", () => {
+    if (__posted === null) return
+    process.stdout.write(__posted.join("\0"))
+    __posted = null
+})